@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use auth::parse_stations;
+use libc;
+
+// set by the SIGHUP handler, cleared once the reload was picked up
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// A block of IPv4 addresses such as `10.0.0.0/8`.
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: u32,
+    mask: u32,
+}
+
+impl Cidr {
+    /// Parse `<address>/<prefix length>`; a bare address is a block of one.
+    pub fn parse(s: &str) -> Option<Cidr> {
+        let (addr, prefix) = match s.find('/') {
+            Some(split) => (&s[..split], s[split + 1..].parse::<u32>().ok()?),
+            None => (s, 32),
+        };
+        if prefix > 32 {
+            return None;
+        }
+        let mask = if prefix == 0 { 0 } else { !0u32 << (32 - prefix) };
+        Some(Cidr {
+            addr: u32::from(addr.parse::<Ipv4Addr>().ok()?) & mask,
+            mask,
+        })
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask == self.addr
+    }
+}
+
+/// Allow and deny rules for one scope, the whole server or a station.
+///
+/// Deny rules win. If there are any allow rules, an address must match one of them too.
+#[derive(Default)]
+struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl Rules {
+    fn add(&mut self, allow: bool, cidr: Cidr) {
+        if allow {
+            self.allow.push(cidr);
+        } else {
+            self.deny.push(cidr);
+        }
+    }
+
+    fn permits(&self, addr: Ipv4Addr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(addr)) &&
+        (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr)))
+    }
+}
+
+/// Which addresses may connect to the server and listen to each station.
+#[derive(Default)]
+pub struct AccessList {
+    server: Rules,
+    stations: HashMap<u16, Rules>,
+}
+
+impl AccessList {
+    /// Load the rules in `path`, one per line as `allow|deny <cidr> [<station>,...]`.
+    ///
+    /// Rules with a list of stations only apply to those stations, the others to connecting at
+    /// all. Blank lines and lines starting with `#` are skipped.
+    pub fn load(path: &str) -> io::Result<AccessList> {
+        let mut acl = AccessList::default();
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || {
+                Error::new(ErrorKind::InvalidData,
+                           format!("{}:{}: expected allow|deny <cidr> [<station>,...]",
+                                   path,
+                                   number + 1))
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 2 || fields.len() > 3 {
+                return Err(invalid());
+            }
+            let allow = match fields[0] {
+                "allow" => true,
+                "deny" => false,
+                _ => return Err(invalid()),
+            };
+            let cidr = Cidr::parse(fields[1]).ok_or_else(invalid)?;
+            match fields.get(2) {
+                Some(stations) => {
+                    for station in parse_stations(stations).ok_or_else(invalid)? {
+                        acl.stations.entry(station).or_default().add(allow, cidr);
+                    }
+                }
+                None => acl.server.add(allow, cidr),
+            }
+        }
+        Ok(acl)
+    }
+
+    /// Whether `addr` may open a control connection.
+    pub fn permits(&self, addr: Ipv4Addr) -> bool {
+        self.server.permits(addr)
+    }
+
+    /// Whether `addr` may listen to `station_number`.
+    pub fn permits_station(&self, addr: Ipv4Addr, station_number: u16) -> bool {
+        self.stations.get(&station_number).is_none_or(|rules| rules.permits(addr))
+    }
+}
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Have SIGHUP request a reload of the access list instead of terminating the server.
+pub fn reload_on_sighup() {
+    unsafe {
+        libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t);
+    }
+}
+
+/// Whether a reload was requested since the last call.
+pub fn take_reload_request() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    /// Write `contents` to a file of its own and load the access list in it.
+    fn load(name: &str, contents: &str) -> io::Result<AccessList> {
+        let path = std::env::temp_dir().join(format!("rustcast-acl-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let acl = AccessList::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        acl
+    }
+
+    fn ip(s: &str) -> Ipv4Addr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn cidrs_contain_the_addresses_their_prefix_covers() {
+        let cases = [("0.0.0.0/0", "0.0.0.0", true),
+                     ("0.0.0.0/0", "255.255.255.255", true),
+                     ("10.1.2.3/0", "192.168.0.1", true),
+                     ("10.1.2.3/32", "10.1.2.3", true),
+                     ("10.1.2.3/32", "10.1.2.4", false),
+                     ("10.1.2.3", "10.1.2.3", true),
+                     ("10.1.2.3", "10.1.2.2", false),
+                     ("10.1.2.3/8", "10.255.0.1", true),
+                     ("10.1.2.3/8", "11.0.0.0", false),
+                     ("192.168.0.0/23", "192.168.1.255", true),
+                     ("192.168.0.0/23", "192.168.2.0", false)];
+        for &(cidr, addr, contained) in &cases {
+            let cidr_block = Cidr::parse(cidr).unwrap();
+            assert_eq!(cidr_block.contains(ip(addr)), contained, "{} {}", cidr, addr);
+        }
+    }
+
+    #[test]
+    fn invalid_cidrs_are_rejected() {
+        for cidr in &["10.0.0.0/33", "10.0.0.0/", "10.0.0.0/-1", "10.0.0.0/8/8", "10.0.0/8",
+                      "/8", "", "localhost", "10.0.0.256/32", "::1/128"] {
+            assert!(Cidr::parse(cidr).is_none(), "{}", cidr);
+        }
+    }
+
+    #[test]
+    fn deny_rules_win_over_overlapping_allow_rules() {
+        let acl = load("overlap",
+                       "allow 10.0.0.0/8\n\
+                        deny 10.1.0.0/16\n\
+                        allow 10.1.2.3/32\n")
+            .unwrap();
+        let cases = [("10.2.0.1", true), ("10.1.0.1", false), ("10.1.2.3", false),
+                     ("192.168.0.1", false)];
+        for &(addr, permitted) in &cases {
+            assert_eq!(acl.permits(ip(addr)), permitted, "{}", addr);
+        }
+    }
+
+    #[test]
+    fn without_allow_rules_everyone_not_denied_is_permitted() {
+        let acl = load("deny-only", "# blocked\n\ndeny 192.168.0.0/16\n").unwrap();
+        assert!(acl.permits(ip("10.0.0.1")));
+        assert!(!acl.permits(ip("192.168.7.7")));
+        assert!(load("empty", "").unwrap().permits(ip("192.168.7.7")));
+    }
+
+    #[test]
+    fn station_rules_only_apply_to_their_stations() {
+        let acl = load("stations",
+                       "deny 0.0.0.0/0\n\
+                        allow 0.0.0.0/0\n\
+                        allow 10.0.0.0/8 1,2\n\
+                        deny 10.9.0.0/16 2\n")
+            .unwrap();
+        // the server wide deny rule wins over the allow rule, for connecting only
+        assert!(!acl.permits(ip("10.0.0.1")));
+        let cases = [("10.0.0.1", 0, true),
+                     ("192.168.0.1", 0, true),
+                     ("10.0.0.1", 1, true),
+                     ("192.168.0.1", 1, false),
+                     ("10.9.0.1", 1, true),
+                     ("10.9.0.1", 2, false),
+                     ("10.0.0.1", 2, true)];
+        for &(addr, station, permitted) in &cases {
+            assert_eq!(acl.permits_station(ip(addr), station), permitted, "{} {}", addr, station);
+        }
+    }
+
+    #[test]
+    fn malformed_rules_are_reported_with_their_line() {
+        let cases = ["permit 10.0.0.0/8", "allow", "allow 10.0.0.0/8 1 2", "allow 10.0.0.0/40",
+                     "deny 10.0.0.0/8 one"];
+        for rule in &cases {
+            let contents = format!("allow 10.0.0.0/8\n{}\n", rule);
+            let e = load("malformed", &contents).err().expect(rule);
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().contains(":2:"), "{}: {}", rule, e);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use eventlog;
+use json;
+
+// after failing, archiving is tried again after this many seconds, doubling up to the maximum
+// while it keeps failing
+const MIN_RETRY_BACKOFF_SECS: u64 = 1;
+const MAX_RETRY_BACKOFF_SECS: u64 = 60;
+
+/// A record of exactly what a station sent, kept in a file per hour.
+///
+/// The audio of the hour starting at, say, 14:00 UTC on 2026-10-19 goes to
+/// `station<N>-2026-10-19T14.mp3` in the archive directory. Next to it,
+/// `station<N>-2026-10-19T14.json` holds a JSON line for the song playing when the file was
+/// opened and for every song change after, with when it happened and the offset in the audio file
+/// it happened at. Both files are only ever appended to, so a restart carries on where it left off.
+pub struct Archive {
+    dir: PathBuf,
+    station: u16,
+
+    // the song playing, to start every file with
+    song_name: String,
+
+    // the files of the hour being archived, if they could be opened
+    files: Option<HourFiles>,
+
+    // when to try opening the files again after failing, and how long to wait if that fails too
+    retry: Option<Instant>,
+    backoff: Duration,
+}
+
+struct HourFiles {
+    // hours since the epoch the files are for
+    hour: u64,
+
+    audio: File,
+    songs: File,
+
+    // bytes written to the audio file
+    offset: u64,
+}
+
+impl Archive {
+    pub fn new(dir: PathBuf, station: u16, song_name: String) -> Archive {
+        Archive {
+            dir,
+            station,
+            song_name,
+            files: None,
+            retry: None,
+            backoff: Duration::from_secs(MIN_RETRY_BACKOFF_SECS),
+        }
+    }
+
+    /// Append `packet` to the audio file, moving on to a new file when the hour is over.
+    ///
+    /// Once writing fails, nothing is archived until opening the files is tried again, after a
+    /// backoff or once the song changes, when the error is returned again if it persists.
+    pub fn write(&mut self, packet: &[u8]) -> io::Result<()> {
+        let now = SystemTime::now();
+        let hour = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 3600;
+        if self.files.as_ref().is_none_or(|files| files.hour != hour) {
+            self.files = None;
+            if self.retry.is_some_and(|retry| Instant::now() < retry) {
+                return Ok(());
+            }
+            match self.open(now, hour) {
+                Ok(files) => {
+                    self.files = Some(files);
+                    self.retry = None;
+                    self.backoff = Duration::from_secs(MIN_RETRY_BACKOFF_SECS);
+                }
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+        let result = match self.files {
+            Some(ref mut files) => {
+                files.audio.write_all(packet).map(|_| files.offset += packet.len() as u64)
+            }
+            None => return Ok(()),
+        };
+        result.map_err(|e| self.fail(e))
+    }
+
+    /// Note that `song_name` starts playing now.
+    pub fn song_change(&mut self, song_name: String) -> io::Result<()> {
+        self.song_name = song_name;
+        let result = match self.files {
+            Some(ref mut files) => record_song(files, &self.song_name),
+            None => {
+                // the files are tried again with the next packet, starting with this song
+                self.retry = None;
+                return Ok(());
+            }
+        };
+        result.map_err(|e| self.fail(e))
+    }
+
+    /// Close the files after `e`, trying to open them again after the backoff.
+    fn fail(&mut self, e: io::Error) -> io::Error {
+        self.files = None;
+        self.retry = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(Duration::from_secs(MAX_RETRY_BACKOFF_SECS));
+        e
+    }
+
+    /// Open the files of `hour`, which `now` is in, starting with the song playing.
+    fn open(&self, now: SystemTime, hour: u64) -> io::Result<HourFiles> {
+        // 2026-10-19T14:05:00.000Z is archived as 2026-10-19T14
+        let name = format!("station{}-{}", self.station, &eventlog::format_time(now)[..13]);
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        let audio = options.open(self.dir.join(format!("{}.mp3", name)))?;
+        let songs = options.open(self.dir.join(format!("{}.json", name)))?;
+        let offset = audio.metadata()?.len();
+        let mut files = HourFiles {
+            hour,
+            audio,
+            songs,
+            offset,
+        };
+        record_song(&mut files, &self.song_name)?;
+        Ok(files)
+    }
+}
+
+fn record_song(files: &mut HourFiles, song_name: &str) -> io::Result<()> {
+    writeln!(files.songs,
+             "{{\"ts\":\"{}\",\"offset\":{},\"song_name\":{}}}",
+             eventlog::format_time(SystemTime::now()),
+             files.offset,
+             json::quote(song_name))
+}
@@ -0,0 +1,190 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind, Read};
+
+use byteorder::{ByteOrder, BigEndian};
+use commands::{MAC_LEN, NONCE_LEN};
+use hmac_sha256::HMAC;
+
+/// A shared secret clients can authenticate with.
+#[derive(Clone)]
+pub struct AuthKey {
+    // identifies the key in AUTH commands
+    pub name: String,
+
+    secret: Vec<u8>,
+
+    // restricted stations the key gives access to; all of them if `None`
+    stations: Option<Vec<u16>>,
+}
+
+impl AuthKey {
+    /// Whether `mac` is the HMAC-SHA256 of `nonce` keyed with this key's secret.
+    pub fn verify(&self, nonce: &[u8; NONCE_LEN], mac: &[u8; MAC_LEN]) -> bool {
+        HMAC::verify(nonce, &self.secret, mac)
+    }
+
+    /// Whether `secret` is the key's secret.
+    ///
+    /// The MACs of both secrets are compared rather than the secrets themselves, so that how long
+    /// it takes tells neither which byte differs nor how long the key's secret is.
+    pub fn check_secret(&self, secret: &[u8]) -> bool {
+        HMAC::verify(secret, &self.secret, &HMAC::mac(&self.secret, &self.secret))
+    }
+
+    /// Whether the key gives access to the restricted station `station_number`.
+    pub fn allows(&self, station_number: u16) -> bool {
+        self.stations.as_ref().is_none_or(|stations| stations.contains(&station_number))
+    }
+}
+
+/// Load the keys in `path`, one per line as `<name> <secret> [<station>,...]`.
+///
+/// Blank lines and lines starting with `#` are skipped. A key without a list of stations gives
+/// access to every restricted station.
+pub fn load_keys(path: &str) -> io::Result<Vec<AuthKey>> {
+    let mut keys = Vec::new();
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || {
+            Error::new(ErrorKind::InvalidData,
+                       format!("{}:{}: expected <name> <secret> [<station>,...]",
+                               path,
+                               number + 1))
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let stations = match fields.len() {
+            2 => None,
+            3 => Some(parse_stations(fields[2]).ok_or_else(invalid)?),
+            _ => return Err(invalid()),
+        };
+        keys.push(AuthKey {
+            name: fields[0].to_string(),
+            secret: fields[1].as_bytes().to_vec(),
+            stations,
+        });
+    }
+    Ok(keys)
+}
+
+/// Parse a comma separated list of station numbers such as `0,2,3`.
+pub fn parse_stations(s: &str) -> Option<Vec<u16>> {
+    s.split(',').map(|station| station.trim().parse::<u16>().ok()).collect()
+}
+
+/// A fresh random nonce for a client to authenticate against.
+pub fn new_nonce() -> io::Result<[u8; NONCE_LEN]> {
+    let mut nonce = [0u8; NONCE_LEN];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(nonce)
+}
+
+/// A fresh random cookie for a UDP challenge.
+pub fn new_cookie() -> io::Result<u64> {
+    let mut cookie = [0u8; 8];
+    File::open("/dev/urandom")?.read_exact(&mut cookie)?;
+    Ok(BigEndian::read_u64(&cookie))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    fn key(secret: &str, stations: Option<Vec<u16>>) -> AuthKey {
+        AuthKey {
+            name: "studio".to_string(),
+            secret: secret.as_bytes().to_vec(),
+            stations,
+        }
+    }
+
+    /// Write `contents` to a file of its own and load the keys in it.
+    fn load(name: &str, contents: &str) -> io::Result<Vec<AuthKey>> {
+        let path = std::env::temp_dir().join(format!("rustcast-auth-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        let keys = load_keys(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        keys
+    }
+
+    #[test]
+    fn verify_accepts_the_hmac_sha256_of_the_nonce() {
+        let mut nonce = [0u8; NONCE_LEN];
+        for (i, byte) in nonce.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        // HMAC-SHA256 of the bytes 0 to 15 keyed with "secret"
+        let mac = [0x75, 0xc4, 0xad, 0x5f, 0xc5, 0xde, 0x34, 0xa8, 0xfb, 0x56, 0x0f, 0x90, 0x7c,
+                   0xda, 0x2b, 0x7e, 0x85, 0xaf, 0x32, 0x29, 0xc3, 0xc9, 0x44, 0x08, 0x4a, 0xb8,
+                   0x23, 0x69, 0xf7, 0x76, 0x41, 0xa6];
+        assert!(key("secret", None).verify(&nonce, &mac));
+        assert!(!key("Secret", None).verify(&nonce, &mac));
+        nonce[0] = 1;
+        assert!(!key("secret", None).verify(&nonce, &mac));
+    }
+
+    #[test]
+    fn check_secret_needs_the_exact_secret() {
+        let key = key("secret", None);
+        assert!(key.check_secret(b"secret"));
+        assert!(!key.check_secret(b"secreT"));
+        assert!(!key.check_secret(b"secre"));
+        assert!(!key.check_secret(b"secrets"));
+        assert!(!key.check_secret(b""));
+    }
+
+    #[test]
+    fn allows_the_listed_stations_or_all() {
+        assert!(key("secret", None).allows(7));
+        let key = key("secret", Some(vec![0, 2]));
+        assert!(key.allows(2));
+        assert!(!key.allows(1));
+    }
+
+    #[test]
+    fn load_keys_skips_comments_and_blank_lines() {
+        let keys = load("valid", "# studio keys\n\nstudio s3cret\n  relay hunter2 1,3  \n")
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0].name, "studio");
+        assert!(keys[0].check_secret(b"s3cret"));
+        assert!(keys[0].allows(5));
+        assert_eq!(keys[1].name, "relay");
+        assert!(keys[1].check_secret(b"hunter2"));
+        assert!(keys[1].allows(3));
+        assert!(!keys[1].allows(2));
+    }
+
+    #[test]
+    fn load_keys_rejects_bad_lines() {
+        for (name, contents) in &[("name-only", "studio\n"),
+                                  ("extra-field", "studio s3cret 1 2\n"),
+                                  ("bad-stations", "studio s3cret 1,x\n")] {
+            let e = load(name, contents).err().unwrap();
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            assert!(e.to_string().ends_with(":1: expected <name> <secret> [<station>,...]"));
+        }
+    }
+
+    #[test]
+    fn load_keys_fails_on_a_missing_file() {
+        let e = load_keys("/nonexistent/rustcast-keys").err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn parse_stations_takes_a_comma_separated_list() {
+        assert_eq!(parse_stations("0"), Some(vec![0]));
+        assert_eq!(parse_stations("0, 2,3"), Some(vec![0, 2, 3]));
+        assert_eq!(parse_stations(""), None);
+        assert_eq!(parse_stations("0,,2"), None);
+        assert_eq!(parse_stations("1,-2"), None);
+        assert_eq!(parse_stations("65536"), None);
+    }
+}
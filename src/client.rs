@@ -0,0 +1,126 @@
+extern crate byteorder;
+extern crate clap;
+extern crate hmac_sha256;
+
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+
+// shared with the server, of which clients only need the constants
+#[allow(dead_code)]
+mod commands;
+mod control;
+mod listen;
+
+use clap::{App, Arg};
+use control::Event;
+use std::io::{self, Write};
+use std::net::UdpSocket;
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc::{Sender, Receiver};
+use std::sync::mpsc;
+use std::thread;
+
+fn main() {
+    env_logger::init().expect("Failed to initialize logger");
+
+    let matches = App::new("rustcast_client")
+        .version("0.1.0")
+        .arg(Arg::with_name("servername")
+            .required(true)
+            .index(1)
+            .help("e.g. localhost OR 10.116.70.158"))
+        .arg(Arg::with_name("serverport").required(true).index(2).help("e.g. 8001"))
+        .arg(Arg::with_name("auth")
+            .long("auth")
+            .takes_value(true)
+            .help("key to authenticate with for restricted stations, as <name>:<secret>"))
+        .arg(Arg::with_name("exec")
+            .long("exec")
+            .takes_value(true)
+            .help("player command fed the audio on its stdin, e.g. \"mpg123 -\"; the audio \
+                   is written to stdout otherwise"))
+        .get_matches();
+
+    let servername = matches.value_of("servername").unwrap();
+    debug!("server: {}", servername);
+    let serverport = matches.value_of("serverport").unwrap().parse::<u16>().unwrap();
+    debug!("server port: {}", serverport);
+    let credentials = matches.value_of("auth").map(|auth| {
+        control::Credentials::parse(auth).unwrap_or_else(|| {
+            eprintln!("Invalid credentials, expected <name>:<secret>");
+            process::exit(1);
+        })
+    });
+
+    // Let the OS pick the UDP port so it always matches the one we announce in HELLO.
+    let socket = UdpSocket::bind(("0.0.0.0", 0)).expect("Failed to bind UDP socket");
+    let udpport = socket.local_addr().unwrap().port();
+    debug!("udp port: {}", udpport);
+
+    let first = match control::connect(servername, serverport, udpport, credentials.as_ref()) {
+        Ok(connected) => Some(connected),
+        Err(e) => {
+            eprintln!("Failed to connect to {}:{}: {}", servername, serverport, e);
+            None
+        }
+    };
+
+    // stdout may be carrying the audio, so everything meant for the user goes to stderr.
+    eprintln!("Type in a number to set the station we're listening to to that number.");
+    eprintln!("Enter ls to list the stations and what they are playing.");
+    eprintln!("Enter np [station] to see how far into its song a station is.");
+    eprintln!("Enter stop to stop the audio without disconnecting.");
+    eprintln!("Enter sink add|rm <host>:<port> to send the audio to more places.");
+    eprintln!("Enter q or press CTRL+C to quit.");
+    if let Some((_, ref welcome)) = first {
+        eprintln!("> The server has {} stations.", welcome.num_stations);
+    }
+
+    let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+
+    let player = match matches.value_of("exec") {
+        Some(cmd) => {
+            let mut player = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .stdin(Stdio::piped())
+                .spawn()
+                .expect("Failed to start player");
+            let sink = player.stdin.take().unwrap();
+            spawn_receiver(socket, sink, tx.clone());
+            Some(player)
+        }
+        None => {
+            spawn_receiver(socket, io::stdout(), tx.clone());
+            None
+        }
+    };
+
+    let mut session = control::Session::new(servername, serverport, udpport, credentials);
+    let session_tx = tx.clone();
+    let session = thread::spawn(move || session.run(first, session_tx, rx, io::stderr()));
+
+    control::input_loop(tx, io::stderr());
+    session.join().ok();
+
+    if let Some(mut player) = player {
+        player.kill().ok();
+        player.wait().ok();
+    }
+}
+
+/// Copy the audio to `sink` on a separate thread, exiting once the sink goes away.
+///
+/// UDP challenges are answered automatically by passing them on to the session through `tx`.
+fn spawn_receiver<W: Write + Send + 'static>(socket: UdpSocket, sink: W, tx: Sender<Event>) {
+    thread::spawn(move || {
+        let on_challenge = |cookie| {
+            tx.send(Event::Verify(cookie)).ok();
+        };
+        if let Err(e) = listen::receive_loop(&socket, sink, on_challenge) {
+            eprintln!("Audio output closed: {}", e);
+            process::exit(1);
+        }
+    });
+}
@@ -1,11 +1,149 @@
+use byteorder::{ByteOrder, BigEndian};
+use std::net::Ipv4Addr;
+
+// Version of the protocol spoken by this server. Clients sending the legacy 3 byte HELLO speak
+// version 0, which has no capabilities.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Capability flags negotiated through HelloExt/WelcomeExt
+pub const CAP_LIST_STATIONS: u16 = 1;
+pub const CAP_NOW_PLAYING: u16 = 1 << 1;
+pub const CAP_KEEPALIVE: u16 = 1 << 2;
+pub const CAP_STOP_QUIT: u16 = 1 << 3;
+pub const CAP_SINKS: u16 = 1 << 4;
+pub const CAP_AUTH: u16 = 1 << 5;
+pub const CAP_STATION_STATUS: u16 = 1 << 6;
+
+// Capabilities supported by this server. CAP_AUTH is only agreed to when keys are configured.
+pub const SERVER_CAPABILITIES: u16 = CAP_LIST_STATIONS | CAP_NOW_PLAYING | CAP_KEEPALIVE |
+                                     CAP_STOP_QUIT | CAP_SINKS | CAP_AUTH | CAP_STATION_STATUS;
+
+// Length of the nonce sent in WelcomeExt when CAP_AUTH is agreed on, and of the HMAC-SHA256 over
+// it that Auth answers with
+pub const NONCE_LEN: usize = 16;
+pub const MAC_LEN: usize = 32;
+
+// Start of the UDP datagram challenging a client to prove it receives on a port, followed by a
+// 64 bit cookie to be echoed back in a Verify
+pub const CHALLENGE_MAGIC: &[u8; 8] = b"RUSTCAST";
+
 // Client to Server Commands
+#[derive(Debug, PartialEq)]
 pub enum ServerCommand {
-    Hello { command_type: u8, udp_port: u16 },
-    SetStation {
-        command_type: u8,
-        station_number: u16,
+    Hello { udp_port: u16 },
+    HelloExt {
+        udp_port: u16,
+        version: u8,
+        capabilities: u16,
+    },
+    SetStation { station_number: u16 },
+    ListStations,
+    NowPlaying { station_number: u16 },
+    Pong { sequence: u16 },
+    // leaves the station without a reply, keeping the connection open for another SetStation
+    Stop,
+    Quit,
+    AddSink { addr: Ipv4Addr, udp_port: u16 },
+    RemoveSink { addr: Ipv4Addr, udp_port: u16 },
+    Auth {
+        key_name: String,
+        mac: [u8; MAC_LEN],
     },
-    Invalid { command_type: u8, unused: u16 },
+    Verify { cookie: u64 },
+    Invalid { command_type: u8 },
+}
+
+impl ServerCommand {
+    /// The capability a client must have agreed on to send this command, if any.
+    pub fn capability(&self) -> Option<u16> {
+        match *self {
+            ServerCommand::ListStations => Some(CAP_LIST_STATIONS),
+            ServerCommand::NowPlaying { .. } => Some(CAP_NOW_PLAYING),
+            ServerCommand::Pong { .. } => Some(CAP_KEEPALIVE),
+            ServerCommand::Stop | ServerCommand::Quit => Some(CAP_STOP_QUIT),
+            ServerCommand::AddSink { .. } |
+            ServerCommand::RemoveSink { .. } => Some(CAP_SINKS),
+            ServerCommand::Auth { .. } => Some(CAP_AUTH),
+            _ => None,
+        }
+    }
+}
+
+/// Parse the first command in `buf`.
+///
+/// Returns the command along with the number of bytes it took up, or `None` if `buf` does not
+/// hold a complete command yet. Commands are three bytes long, a command type followed by a
+/// 16 bit value, except for `HelloExt` which appends a version and capability flags,
+/// `AddSink`/`RemoveSink` which append an IPv4 address to the port, `Auth` which carries a
+/// length prefixed key name followed by a MAC, and `Verify` which carries a 64 bit cookie.
+pub fn parse_command(buf: &[u8]) -> Option<(ServerCommand, usize)> {
+    if buf.len() < 3 {
+        return None;
+    }
+
+    let command_type = buf[0];
+    let command_value = BigEndian::read_u16(&buf[1..3]);
+    let command = match command_type {
+        0 => ServerCommand::Hello { udp_port: command_value },
+        1 => ServerCommand::SetStation { station_number: command_value },
+        2 => ServerCommand::ListStations,
+        3 => ServerCommand::NowPlaying { station_number: command_value },
+        4 => {
+            if buf.len() < 6 {
+                return None;
+            }
+            let command = ServerCommand::HelloExt {
+                udp_port: command_value,
+                version: buf[3],
+                capabilities: BigEndian::read_u16(&buf[4..6]),
+            };
+            return Some((command, 6));
+        }
+        5 => ServerCommand::Pong { sequence: command_value },
+        6 => ServerCommand::Stop,
+        7 => ServerCommand::Quit,
+        8 | 9 => {
+            if buf.len() < 7 {
+                return None;
+            }
+            let addr = Ipv4Addr::new(buf[3], buf[4], buf[5], buf[6]);
+            let command = if command_type == 8 {
+                ServerCommand::AddSink {
+                    addr,
+                    udp_port: command_value,
+                }
+            } else {
+                ServerCommand::RemoveSink {
+                    addr,
+                    udp_port: command_value,
+                }
+            };
+            return Some((command, 7));
+        }
+        10 => {
+            let key_name_end = 2 + buf[1] as usize;
+            let len = key_name_end + MAC_LEN;
+            if buf.len() < len {
+                return None;
+            }
+            let mut mac = [0u8; MAC_LEN];
+            mac.copy_from_slice(&buf[key_name_end..len]);
+            let command = ServerCommand::Auth {
+                key_name: String::from_utf8_lossy(&buf[2..key_name_end]).into_owned(),
+                mac,
+            };
+            return Some((command, len));
+        }
+        11 => {
+            if buf.len() < 9 {
+                return None;
+            }
+            return Some((ServerCommand::Verify { cookie: BigEndian::read_u64(&buf[1..9]) }, 9));
+        }
+        _ => ServerCommand::Invalid { command_type },
+    };
+
+    Some((command, 3))
 }
 
 // Server to Client Replies
@@ -15,6 +153,16 @@ pub struct Welcome {
     pub num_stations: u16,
 }
 
+// Reply to a HelloExt, carrying the version and capabilities both sides support
+#[allow(dead_code)]
+pub struct WelcomeExt {
+    pub reply_type: u8,
+    pub num_stations: u16,
+    pub version: u8,
+    pub capabilities: u16,
+    // followed by nonce: [u8; NONCE_LEN] if capabilities include CAP_AUTH
+}
+
 #[allow(dead_code)]
 pub struct Announce {
     pub reply_type: u8,
@@ -28,3 +176,153 @@ pub struct InvalidCommand {
     pub reply_string_size: u8,
     pub reply_string: [u8],
 }
+
+// One entry of a StationList, repeated num_stations times
+#[allow(dead_code)]
+pub struct StationEntry {
+    pub station_number: u16,
+    pub name_size: u8,
+    pub name: [u8],
+    // followed by song_name_size: u8 and song_name: [u8], and by status: u8 as in StationStatus
+    // if capabilities include CAP_STATION_STATUS
+}
+
+#[allow(dead_code)]
+pub struct StationList {
+    pub reply_type: u8,
+    pub num_stations: u16,
+    // followed by num_stations StationEntry
+}
+
+#[allow(dead_code)]
+pub struct NowPlaying {
+    pub reply_type: u8,
+    pub station_number: u16,
+    pub song_name_size: u8,
+    pub song_name: [u8],
+    // followed by elapsed_ms: u32 and duration_ms: u32
+}
+
+// Sent periodically to clients that negotiated CAP_KEEPALIVE, to be answered with a Pong
+// carrying the same sequence number
+#[allow(dead_code)]
+pub struct Ping {
+    pub reply_type: u8,
+    pub sequence: u16,
+}
+
+// Sent to clients that negotiated CAP_STATION_STATUS when the health of their station changes,
+// and after an Announce for a station that is not playing its own source
+#[allow(dead_code)]
+pub struct StationStatus {
+    pub reply_type: u8,
+    pub station_number: u16,
+    // 0 if the station is offline, 1 if it plays its own source and 2 if it plays its fallback
+    pub status: u8,
+}
+
+// Answer to a Quit, sent right before the server closes the connection
+#[allow(dead_code)]
+pub struct Goodbye {
+    pub reply_type: u8,
+    pub unused: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A command of every type as sent by a client, along with what it parses to.
+    fn commands() -> Vec<(Vec<u8>, ServerCommand)> {
+        let mut auth = vec![10, 3];
+        auth.extend_from_slice(b"key");
+        auth.extend_from_slice(&[7; MAC_LEN]);
+        let sink = Ipv4Addr::new(10, 0, 0, 1);
+        vec![(vec![0, 0x1f, 0x90], ServerCommand::Hello { udp_port: 8080 }),
+             (vec![1, 0, 2], ServerCommand::SetStation { station_number: 2 }),
+             (vec![2, 0, 0], ServerCommand::ListStations),
+             (vec![3, 0, 1], ServerCommand::NowPlaying { station_number: 1 }),
+             (vec![4, 0x1f, 0x90, 1, 0, 0x7f],
+              ServerCommand::HelloExt {
+                  udp_port: 8080,
+                  version: 1,
+                  capabilities: 0x7f,
+              }),
+             (vec![5, 0x12, 0x34], ServerCommand::Pong { sequence: 0x1234 }),
+             (vec![6, 0, 0], ServerCommand::Stop),
+             (vec![7, 0, 0], ServerCommand::Quit),
+             (vec![8, 0x1f, 0x90, 10, 0, 0, 1],
+              ServerCommand::AddSink {
+                  addr: sink,
+                  udp_port: 8080,
+              }),
+             (vec![9, 0x1f, 0x90, 10, 0, 0, 1],
+              ServerCommand::RemoveSink {
+                  addr: sink,
+                  udp_port: 8080,
+              }),
+             (auth,
+              ServerCommand::Auth {
+                  key_name: "key".to_string(),
+                  mac: [7; MAC_LEN],
+              }),
+             (vec![11, 1, 2, 3, 4, 5, 6, 7, 8],
+              ServerCommand::Verify { cookie: 0x0102_0304_0506_0708 }),
+             (vec![12, 0, 0], ServerCommand::Invalid { command_type: 12 })]
+    }
+
+    #[test]
+    fn every_command_parses_from_a_buffer_of_exactly_its_length() {
+        for (buf, command) in commands() {
+            assert_eq!(parse_command(&buf), Some((command, buf.len())));
+        }
+    }
+
+    #[test]
+    fn short_buffers_wait_for_the_rest_of_the_command() {
+        for (buf, _) in commands() {
+            for len in 0..buf.len() {
+                assert_eq!(parse_command(&buf[..len]), None, "{:?}", &buf[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn over_long_buffers_leave_what_follows_the_command() {
+        for (mut buf, command) in commands() {
+            let len = buf.len();
+            buf.extend_from_slice(&[0xff; 40]);
+            assert_eq!(parse_command(&buf), Some((command, len)));
+        }
+    }
+
+    #[test]
+    fn commands_back_to_back_parse_one_after_the_other() {
+        let commands = commands();
+        for (first, first_command) in &commands {
+            for (second, second_command) in &commands {
+                let mut buf = first.clone();
+                buf.extend_from_slice(second);
+                let (command, len) = parse_command(&buf).unwrap();
+                assert_eq!((&command, len), (first_command, first.len()));
+                let (command, len) = parse_command(&buf[len..]).unwrap();
+                assert_eq!((&command, len), (second_command, second.len()));
+            }
+        }
+    }
+
+    #[test]
+    fn auth_key_names_are_prefixed_with_their_length() {
+        for &name_len in &[0usize, 255] {
+            let mut buf = vec![10, name_len as u8];
+            buf.extend(vec![b'k'; name_len]);
+            buf.extend_from_slice(&[1; MAC_LEN]);
+            let expected = ServerCommand::Auth {
+                key_name: "k".repeat(name_len),
+                mac: [1; MAC_LEN],
+            };
+            assert_eq!(parse_command(&buf), Some((expected, 2 + name_len + MAC_LEN)));
+            assert_eq!(parse_command(&buf[..buf.len() - 1]), None);
+        }
+    }
+}
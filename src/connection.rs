@@ -1,14 +1,23 @@
+use std::collections::VecDeque;
 use std::io;
 use std::io::prelude::*;
 use std::io::{Error, ErrorKind};
 use std::rc::Rc;
 use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
 
 use byteorder::{ByteOrder, BigEndian};
 use commands::*;
 
 use mio::*;
-use mio::tcp::*;
+use mio::net::TcpStream;
+use mio::unix::UnixReady;
+
+/// Address and port of a UDP sink receiving a station's audio.
+pub type UdpAddress = (Ipv4Addr, u16);
+
+// sinks registered with ADD_SINK, on top of the one announced in HELLO
+const MAX_EXTRA_SINKS: usize = 8;
 
 /// A stateful wrapper around a non-blocking stream. This connection is not
 /// the SERVER connection. This connection represents the client connections
@@ -23,8 +32,14 @@ pub struct Connection {
     // set of events we are interested in
     interest: Ready,
 
+    // bytes received but not yet parsed into a command
+    recv_buf: Vec<u8>,
+
     // messages waiting to be sent out
-    send_queue: Vec<Rc<Vec<u8>>>,
+    send_queue: VecDeque<Rc<Vec<u8>>>,
+
+    // bytes of the message at the front of the queue that were already sent
+    send_offset: usize,
 
     // track whether a connection needs to be (re)registered
     is_idle: bool,
@@ -36,27 +51,65 @@ pub struct Connection {
 
     handshake_done: bool,
 
-    current_channel: u16,
+    current_channel: Option<u16>,
 
     udp_port: u16,
 
     addr: Ipv4Addr,
+
+    // sinks added with ADD_SINK, possibly on other hosts
+    extra_sinks: Vec<UdpAddress>,
+
+    // capabilities agreed on with an extended HELLO
+    capabilities: u16,
+
+    // sequence number of the last PING sent and when it was sent
+    ping_sequence: u16,
+
+    last_ping: Instant,
+
+    // when the client last answered a PING, or finished the handshake
+    last_pong: Instant,
+
+    // sent in WELCOME for the client to authenticate against, if CAP_AUTH was agreed on
+    nonce: Option<[u8; NONCE_LEN]>,
+
+    // index of the key the client authenticated with in the server's settings
+    auth_key: Option<usize>,
+
+    // sinks sent a UDP challenge that was not answered yet, with the cookie expected back, when
+    // it was last sent and how often it was sent
+    challenges: Vec<(UdpAddress, u64, Instant, u32)>,
+
+    // sinks that answered their challenge
+    verified: Vec<UdpAddress>,
 }
 
 impl Connection {
     pub fn new(sock: TcpStream, token: Token, addr: Ipv4Addr) -> Connection {
         Connection {
-            sock: sock,
-            token: token,
-            interest: Ready::hup(),
-            send_queue: Vec::new(),
+            sock,
+            token,
+            interest: Ready::from(UnixReady::hup()),
+            recv_buf: Vec::new(),
+            send_queue: VecDeque::new(),
+            send_offset: 0,
             is_idle: true,
             is_reset: false,
             is_to_be_removed: false,
             handshake_done: false,
-            current_channel: 65535,
+            current_channel: None,
             udp_port: 0,
-            addr: addr,
+            addr,
+            extra_sinks: Vec::new(),
+            capabilities: 0,
+            ping_sequence: 0,
+            last_ping: Instant::now(),
+            last_pong: Instant::now(),
+            nonce: None,
+            auth_key: None,
+            challenges: Vec::new(),
+            verified: Vec::new(),
         }
     }
 
@@ -71,86 +124,67 @@ impl Connection {
     }
 
     fn read_command(&mut self) -> io::Result<Option<ServerCommand>> {
-        let mut buf = [0u8; 3];
-
-        let bytes = match self.sock.read(&mut buf) {
-            Ok(n) => n,
-            Err(e) => {
-                if e.kind() == ErrorKind::WouldBlock {
-                    return Ok(None);
-                } else {
-                    return Err(e);
-                }
+        loop {
+            if let Some((command, len)) = parse_command(&self.recv_buf) {
+                self.recv_buf.drain(..len);
+                return Ok(Some(command));
             }
-        };
-
-        if bytes < 3 {
-            warn!("Found message length of {} bytes", bytes);
-            return Err(Error::new(ErrorKind::InvalidData, "Invalid message length"));
-        }
 
-        let command_type = buf[0];
-        let command_value = BigEndian::read_u16(&buf[1..]);
-        let command = match command_type {
-            0 => {
-                ServerCommand::Hello {
-                    command_type: command_type,
-                    udp_port: command_value,
-                }
-            }
-            1 => {
-                ServerCommand::SetStation {
-                    command_type: command_type,
-                    station_number: command_value,
+            let mut buf = [0u8; 64];
+            match self.sock.read(&mut buf) {
+                Ok(0) => {
+                    warn!("Connection closed with {} bytes pending", self.recv_buf.len());
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "Connection closed"));
                 }
-            }
-            _ => {
-                ServerCommand::Invalid {
-                    command_type: 99,
-                    unused: 99,
+                Ok(n) => self.recv_buf.extend_from_slice(&buf[..n]),
+                Err(e) => {
+                    if e.kind() == ErrorKind::WouldBlock {
+                        return Ok(None);
+                    } else {
+                        return Err(e);
+                    }
                 }
             }
-        };
-
-        Ok(Some(command))
+        }
     }
 
     /// Handle a writable event from the poller.
     ///
-    /// Send one message from the send queue to the client. If the queue is empty, remove interest
-    /// in write events.
+    /// Send one message from the send queue to the client, or as much of it as the socket takes.
+    /// If the queue is empty, remove interest in write events.
     pub fn writable(&mut self) -> io::Result<()> {
 
-        try!(self.send_queue
-            .pop()
-            .ok_or(Error::new(ErrorKind::Other, "Could not pop send queue"))
-            .and_then(|buf| {
-
-                match self.sock.write(&*buf) {
-                    Ok(n) => {
-                        debug!("CONN : we wrote {} bytes", n);
-                        Ok(())
-                    }
-                    Err(e) => {
-                        if e.kind() == ErrorKind::WouldBlock {
-                            debug!("client flushing buf; WouldBlock");
-
-                            // put message back into the queue so we can try again
-                            self.send_queue.push(buf);
-                            Ok(())
-                        } else {
-                            error!("Failed to send buffer for {:?}, error: {}", self.token, e);
-                            Err(e)
-                        }
-                    }
+        let buf = self.send_queue
+            .front()
+            .cloned()
+            .ok_or_else(|| Error::other("Could not pop send queue"))?;
+
+        match self.sock.write(&buf[self.send_offset..]) {
+            Ok(n) => {
+                debug!("CONN : we wrote {} bytes", n);
+                self.send_offset += n;
+                if self.send_offset == buf.len() {
+                    self.send_queue.pop_front();
+                    self.send_offset = 0;
+                }
+                // otherwise the rest of the message stays at the front of the queue
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::WouldBlock {
+                    // the message stays at the front of the queue so we can try again
+                    debug!("client flushing buf; WouldBlock");
+                } else {
+                    error!("Failed to send buffer for {:?}, error: {}", self.token, e);
+                    return Err(e);
                 }
-            }));
+            }
+        }
 
         if self.send_queue.is_empty() {
             self.interest.remove(Ready::writable());
         }
 
-        if self.is_to_be_removed() {
+        if self.is_to_be_removed() && self.send_queue.is_empty() {
             debug!("Marking for reset: {:?}", self.token);
             self.mark_reset();
         }
@@ -166,7 +200,7 @@ impl Connection {
     pub fn send_message(&mut self, message: Rc<Vec<u8>>) -> io::Result<()> {
         trace!("connection send_message; token={:?}", self.token);
 
-        self.send_queue.push(message);
+        self.send_queue.push_back(message);
 
         if !self.interest.is_writable() {
             self.interest.insert(Ready::writable());
@@ -187,13 +221,12 @@ impl Connection {
                       self.token,
                       self.interest,
                       PollOpt::edge() | PollOpt::oneshot())
-            .and_then(|()| {
+            .map(|()| {
                 self.is_idle = false;
-                Ok(())
             })
-            .or_else(|e| {
+            .map_err(|e| {
                 error!("Failed to reregister {:?}, {:?}", self.token, e);
-                Err(e)
+                e
             })
     }
 
@@ -205,13 +238,12 @@ impl Connection {
                         self.token,
                         self.interest,
                         PollOpt::edge() | PollOpt::oneshot())
-            .and_then(|()| {
+            .map(|()| {
                 self.is_idle = false;
-                Ok(())
             })
-            .or_else(|e| {
+            .map_err(|e| {
                 error!("Failed to reregister {:?}, {:?}", self.token, e);
-                Err(e)
+                e
             })
     }
 
@@ -259,26 +291,161 @@ impl Connection {
         self.handshake_done
     }
 
-    pub fn set_current_channel(&mut self, channel: u16) {
+    pub fn set_current_channel(&mut self, channel: Option<u16>) {
         self.current_channel = channel;
     }
 
     #[inline]
-    pub fn get_current_channel(&self) -> u16 {
+    pub fn get_current_channel(&self) -> Option<u16> {
         self.current_channel
     }
 
+    #[inline]
+    pub fn get_addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
     pub fn set_udp_port(&mut self, port: u16) {
         self.udp_port = port;
     }
 
+    /// Every sink the audio of this connection goes to: the peer's HELLO port and any sinks
+    /// added since, leaving out those with an unanswered challenge.
+    pub fn get_sinks(&self) -> Vec<UdpAddress> {
+        let mut sinks = vec![(self.addr, self.udp_port)];
+        sinks.extend(self.extra_sinks.iter().filter(|&&sink| sink != (self.addr, self.udp_port)));
+        sinks.retain(|&sink| !self.is_challenged(sink));
+        sinks
+    }
+
+    /// Add a sink, returning false if the connection already has as many as it may.
+    pub fn add_sink(&mut self, sink: UdpAddress) -> bool {
+        if self.extra_sinks.contains(&sink) {
+            return true;
+        }
+        if self.extra_sinks.len() >= MAX_EXTRA_SINKS {
+            return false;
+        }
+        self.extra_sinks.push(sink);
+        true
+    }
+
+    pub fn remove_sink(&mut self, sink: UdpAddress) {
+        self.extra_sinks.retain(|&s| s != sink);
+        if sink != (self.addr, self.udp_port) {
+            self.challenges.retain(|&(s, ..)| s != sink);
+            self.verified.retain(|&s| s != sink);
+        }
+    }
+
+    /// The sinks of `get_sinks` that answered a challenge, proving that the client receives on
+    /// them.
+    pub fn get_verified_sinks(&self) -> Vec<UdpAddress> {
+        let mut sinks = self.get_sinks();
+        sinks.retain(|sink| self.verified.contains(sink));
+        sinks
+    }
+
+    /// Hold back the audio for `sink` until the client echoes `cookie`, which was just sent.
+    pub fn add_challenge(&mut self, sink: UdpAddress, cookie: u64) {
+        self.challenges.retain(|&(s, ..)| s != sink);
+        self.challenges.push((sink, cookie, Instant::now(), 1));
+    }
+
+    /// Whether `sink` has a challenge that was not answered yet.
+    pub fn is_challenged(&self, sink: UdpAddress) -> bool {
+        self.challenges.iter().any(|&(s, ..)| s == sink)
+    }
+
+    /// Unanswered challenges last sent at least `interval` ago, which are taken as being sent
+    /// again now.
+    ///
+    /// Challenges that were sent `max_sends` times already are given up on instead, and their
+    /// sinks returned separately. Those sinks stay challenged so that they get no audio.
+    pub fn take_due_challenges(&mut self,
+                               interval: Duration,
+                               max_sends: u32)
+                               -> (Vec<(UdpAddress, u64)>, Vec<UdpAddress>) {
+        let now = Instant::now();
+        let mut due = Vec::new();
+        let mut unanswered = Vec::new();
+        for challenge in self.challenges.iter_mut() {
+            if now.duration_since(challenge.2) < interval {
+                continue;
+            }
+            challenge.2 = now;
+            if challenge.3 >= max_sends {
+                unanswered.push(challenge.0);
+            } else {
+                challenge.3 += 1;
+                due.push((challenge.0, challenge.1));
+            }
+        }
+        (due, unanswered)
+    }
+
+    /// Answer the challenge `cookie` was sent in, returning the sink it proved to be reachable.
+    pub fn verify(&mut self, cookie: u64) -> Option<UdpAddress> {
+        let index = self.challenges.iter().position(|&(_, c, ..)| c == cookie)?;
+        let sink = self.challenges.remove(index).0;
+        if !self.verified.contains(&sink) {
+            self.verified.push(sink);
+        }
+        Some(sink)
+    }
+
+    pub fn set_capabilities(&mut self, capabilities: u16) {
+        self.capabilities = capabilities;
+    }
+
     #[inline]
-    pub fn get_udp_port(&self) -> u16 {
-        self.udp_port
+    pub fn has_capability(&self, capability: u16) -> bool {
+        self.capabilities & capability != 0
+    }
+
+    /// Queue a PING, returning its sequence number.
+    pub fn send_ping(&mut self) -> io::Result<u16> {
+        self.ping_sequence = self.ping_sequence.wrapping_add(1);
+        self.last_ping = Instant::now();
+
+        let mut pingbuf = vec![0u8; 3];
+        pingbuf[0] = 6; // reply_type
+        BigEndian::write_u16(&mut pingbuf[1..], self.ping_sequence);
+        self.send_message(Rc::new(pingbuf))?;
+        Ok(self.ping_sequence)
+    }
+
+    /// Record a PONG from the client. Answers to older PINGs are accepted too, as they still
+    /// prove the client is alive.
+    pub fn mark_pong(&mut self) {
+        self.last_pong = Instant::now();
     }
 
     #[inline]
-    pub fn get_addr(&self) -> Ipv4Addr {
-        self.addr
+    pub fn get_last_ping(&self) -> Instant {
+        self.last_ping
+    }
+
+    #[inline]
+    pub fn get_last_pong(&self) -> Instant {
+        self.last_pong
+    }
+
+    pub fn set_nonce(&mut self, nonce: [u8; NONCE_LEN]) {
+        self.nonce = Some(nonce);
+    }
+
+    #[inline]
+    pub fn get_nonce(&self) -> Option<[u8; NONCE_LEN]> {
+        self.nonce
+    }
+
+    pub fn set_auth_key(&mut self, auth_key: usize) {
+        self.auth_key = Some(auth_key);
+    }
+
+    #[inline]
+    pub fn get_auth_key(&self) -> Option<usize> {
+        self.auth_key
     }
 }
@@ -0,0 +1,1019 @@
+use byteorder::{ByteOrder, BigEndian};
+use commands::{CAP_AUTH, CAP_KEEPALIVE, CAP_LIST_STATIONS, CAP_NOW_PLAYING, CAP_SINKS,
+               CAP_STATION_STATUS, CAP_STOP_QUIT, NONCE_LEN, PROTOCOL_VERSION};
+use hmac_sha256::HMAC;
+use std::cmp;
+use std::io::{self, Error, ErrorKind};
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
+use std::thread;
+
+/// Delay before the first reconnect attempt; doubled after every failure.
+const INITIAL_BACKOFF_SECS: u64 = 1;
+
+/// Upper bound for the delay between two reconnect attempts.
+const MAX_BACKOFF_SECS: u64 = 30;
+
+/// Capabilities supported by the clients.
+const CLIENT_CAPABILITIES: u16 = CAP_LIST_STATIONS | CAP_NOW_PLAYING | CAP_KEEPALIVE |
+                                 CAP_STOP_QUIT | CAP_SINKS | CAP_AUTH | CAP_STATION_STATUS;
+
+/// How long to wait for the server's WELCOME before giving up on it.
+const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+
+/// How long to wait for the server's GOODBYE after sending QUIT.
+const GOODBYE_TIMEOUT_MS: u64 = 1000;
+
+/// Everything a control session reacts to, multiplexed onto a single channel.
+///
+/// Replies are tagged with the connection they were read from so that a session can ignore
+/// whatever the reader of a connection it already gave up on still delivers.
+pub enum Event {
+    /// The user asked for a station.
+    SetStation(u16),
+    /// The user asked for the list of stations.
+    ListStations,
+    /// The user asked what a station, by default the current one, is playing.
+    NowPlaying(Option<u16>),
+    /// The user wants the audio to stop but the session to stay open.
+    Stop,
+    /// The user wants the audio sent to another UDP sink as well.
+    AddSink(SocketAddrV4),
+    /// The user no longer wants the audio sent to a sink added before.
+    RemoveSink(SocketAddrV4),
+    /// A UDP challenge with the given cookie arrived at one of our sinks.
+    Verify(u64),
+    /// The user is done.
+    Quit,
+    /// The server sent a reply on the given connection.
+    Reply(usize, Reply),
+    /// The given connection was closed or failed.
+    Disconnected(usize),
+}
+
+/// The server's answer to our HELLO.
+///
+/// Servers that only understand the legacy HELLO speak protocol version 0 without any
+/// capabilities.
+pub struct Welcome {
+    pub num_stations: u16,
+    pub version: u8,
+    pub capabilities: u16,
+    pub nonce: Option<[u8; NONCE_LEN]>,
+}
+
+/// A key shared with the server, used to authenticate for restricted stations.
+#[derive(Clone)]
+pub struct Credentials {
+    key_name: String,
+    secret: String,
+}
+
+impl Credentials {
+    /// Parse credentials given as `<name>:<secret>`.
+    pub fn parse(s: &str) -> Option<Credentials> {
+        let split = s.find(':')?;
+        if split == 0 || split > 255 {
+            return None;
+        }
+        Some(Credentials {
+            key_name: s[..split].to_string(),
+            secret: s[split + 1..].to_string(),
+        })
+    }
+}
+
+/// Connect to a rustcast server, perform the HELLO/WELCOME handshake and authenticate with
+/// `credentials` if given.
+///
+/// Authentication is skipped with a warning when the server does not offer it. Should the server
+/// reject the credentials, its INVALID_COMMAND shows up as the next reply.
+pub fn connect(servername: &str,
+               serverport: u16,
+               udpport: u16,
+               credentials: Option<&Credentials>)
+               -> io::Result<(TcpStream, Welcome)> {
+    let (mut stream, welcome) = hello(servername, serverport, udpport)?;
+    // replies are read without a timeout once the handshake is done
+    stream.set_read_timeout(None)?;
+
+    if let Some(credentials) = credentials {
+        match welcome.nonce {
+            Some(nonce) => send_auth(&mut stream, credentials, &nonce)?,
+            None => warn!("Server does not offer authentication; continuing without"),
+        }
+    }
+
+    Ok((stream, welcome))
+}
+
+/// Perform the HELLO/WELCOME handshake.
+///
+/// An extended HELLO advertising our protocol version and capabilities is tried first. Servers
+/// predating it reject that with an INVALID_COMMAND, in which case we connect again with the
+/// legacy HELLO.
+fn hello(servername: &str, serverport: u16, udpport: u16) -> io::Result<(TcpStream, Welcome)> {
+    let mut stream = TcpStream::connect((servername, serverport))?;
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+
+    let mut hellobuf = [0u8; 6];
+    hellobuf[0] = 4;
+    BigEndian::write_u16(&mut hellobuf[1..3], udpport);
+    hellobuf[3] = PROTOCOL_VERSION;
+    BigEndian::write_u16(&mut hellobuf[4..], CLIENT_CAPABILITIES);
+    debug!("{:?}", hellobuf);
+    stream.write_all(hellobuf.as_ref())?;
+
+    let welcome = match read_reply(&mut stream, 0)? {
+        Reply::Welcome(welcome) => welcome,
+        Reply::InvalidCommand { .. } => {
+            info!("Server does not understand the extended HELLO; falling back to legacy HELLO");
+            return connect_legacy(servername, serverport, udpport);
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Expected WELCOME")),
+    };
+    info!("num_stations: {}, version: {}, capabilities: {:#06x}",
+          welcome.num_stations,
+          welcome.version,
+          welcome.capabilities);
+
+    Ok((stream, welcome))
+}
+
+/// Connect with the legacy 3 byte HELLO understood by every server.
+fn connect_legacy(servername: &str,
+                  serverport: u16,
+                  udpport: u16)
+                  -> io::Result<(TcpStream, Welcome)> {
+    let mut stream = TcpStream::connect((servername, serverport))?;
+    stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_SECS)))?;
+
+    let mut hellobuf = [0u8; 3];
+    BigEndian::write_u16(&mut hellobuf[1..], udpport);
+    debug!("{:?}", hellobuf);
+    stream.write_all(hellobuf.as_ref())?;
+
+    let welcome = match read_reply(&mut stream, 0)? {
+        Reply::Welcome(welcome) => welcome,
+        Reply::InvalidCommand { reply_string } => {
+            return Err(Error::other(format!("HELLO rejected: {}", reply_string)));
+        }
+        _ => return Err(Error::new(ErrorKind::InvalidData, "Expected WELCOME")),
+    };
+    info!("num_stations: {}", welcome.num_stations);
+
+    Ok((stream, welcome))
+}
+
+/// Read station numbers typed by the user and forward them to the `Session`.
+///
+/// Returns once the user quits or stdin is closed, after telling the session with `Event::Quit`.
+pub fn input_loop<W: Write>(tx: Sender<Event>, mut out: W) {
+    loop {
+        write!(out, "> ").unwrap();
+        out.flush().unwrap();
+
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) => {
+                info!("EndOfFile sent (Ctrl-D)");
+                break;
+            }
+            Ok(_) => {
+                match input.trim() {
+                    "q" | "quit" => break,
+                    "stop" => {
+                        if tx.send(Event::Stop).is_err() {
+                            break;
+                        }
+                    }
+                    "ls" => {
+                        if tx.send(Event::ListStations).is_err() {
+                            break;
+                        }
+                    }
+                    x if x.starts_with("sink ") => {
+                        let mut words = x.split_whitespace().skip(1);
+                        let (action, sink) = match (words.next(), words.next().and_then(resolve)) {
+                            (Some(action), Some(sink)) => (action, sink),
+                            _ => {
+                                writeln!(out, "Invalid input: sink add|rm <host>:<port> expected")
+                                    .unwrap();
+                                continue;
+                            }
+                        };
+                        let event = match action {
+                            "add" => Event::AddSink(sink),
+                            "rm" => Event::RemoveSink(sink),
+                            _ => {
+                                writeln!(out, "Invalid input: sink add|rm <host>:<port> expected")
+                                    .unwrap();
+                                continue;
+                            }
+                        };
+                        if tx.send(event).is_err() {
+                            break;
+                        }
+                    }
+                    x if x.starts_with("verify ") => {
+                        let cookie = match u64::from_str_radix(x[7..].trim(), 16) {
+                            Ok(cookie) => cookie,
+                            Err(_) => {
+                                writeln!(out, "Invalid input: verify <code> expected").unwrap();
+                                continue;
+                            }
+                        };
+                        if tx.send(Event::Verify(cookie)).is_err() {
+                            break;
+                        }
+                    }
+                    x if x == "np" || x.starts_with("np ") => {
+                        let station = match x[2..].trim() {
+                            "" => None,
+                            station => {
+                                match station.parse::<u16>() {
+                                    Ok(num) => Some(num),
+                                    Err(_) => {
+                                        writeln!(out, "Invalid input: np [station] expected")
+                                            .unwrap();
+                                        continue;
+                                    }
+                                }
+                            }
+                        };
+                        if tx.send(Event::NowPlaying(station)).is_err() {
+                            break;
+                        }
+                    }
+                    x => {
+                        let stationres = x.parse::<u16>();
+                        let station = match stationres {
+                            Ok(num) => num,
+                            Err(_) => {
+                                writeln!(out,
+                                         "Invalid input: number, 'ls', 'np', 'stop' or 'q' \
+                                          expected")
+                                    .unwrap();
+                                continue;
+                            }
+                        };
+                        if tx.send(Event::SetStation(station)).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(_) => {
+                panic!("Unexpected error reading from stdin");
+            }
+        }
+    }
+
+    tx.send(Event::Quit).ok();
+}
+
+/// A reply sent by the server on the control connection.
+pub enum Reply {
+    Welcome(Welcome),
+    Announce { song_name: String },
+    InvalidCommand { reply_string: String },
+    StationList { stations: Vec<StationEntry> },
+    NowPlaying {
+        station_number: u16,
+        song_name: String,
+        elapsed_ms: u32,
+        duration_ms: u32,
+    },
+    Ping { sequence: u16 },
+    StationStatus { station_number: u16, health: Health },
+    Goodbye,
+    Unknown { reply_type: u8 },
+}
+
+/// A station as described by a STATION_LIST reply.
+pub struct StationEntry {
+    pub station_number: u16,
+    pub name: String,
+    pub song_name: String,
+
+    // whether the station is up, if CAP_STATION_STATUS was agreed on
+    pub health: Option<Health>,
+}
+
+/// Whether a station plays, as told by STATION_LIST and STATION_STATUS.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    Offline,
+    Online,
+    // playing a fallback source while its own fails
+    Fallback,
+}
+
+impl Health {
+    fn from_byte(status: u8) -> Health {
+        match status {
+            0 => Health::Offline,
+            2 => Health::Fallback,
+            _ => Health::Online,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Offline => "offline",
+            Health::Online => "online",
+            Health::Fallback => "fallback",
+        }
+    }
+}
+
+/// Ask the server to tune this client into `station`.
+pub fn send_set_station(stream: &mut TcpStream, station: u16) -> io::Result<()> {
+    let mut setstationbuf = [0u8; 3];
+    setstationbuf[0] = 1;
+    BigEndian::write_u16(&mut setstationbuf[1..], station);
+    debug!("{:?}", setstationbuf);
+    stream.write_all(setstationbuf.as_ref())
+}
+
+/// Ask the server for the list of its stations.
+pub fn send_list_stations(stream: &mut TcpStream) -> io::Result<()> {
+    let liststationsbuf = [2u8, 0, 0];
+    stream.write_all(liststationsbuf.as_ref())
+}
+
+/// Ask the server what `station` is playing and how far into the song it is.
+pub fn send_now_playing(stream: &mut TcpStream, station: u16) -> io::Result<()> {
+    let mut nowplayingbuf = [0u8; 3];
+    nowplayingbuf[0] = 3;
+    BigEndian::write_u16(&mut nowplayingbuf[1..], station);
+    stream.write_all(nowplayingbuf.as_ref())
+}
+
+/// Answer the server's PING with the given sequence number.
+pub fn send_pong(stream: &mut TcpStream, sequence: u16) -> io::Result<()> {
+    let mut pongbuf = [0u8; 3];
+    pongbuf[0] = 5;
+    BigEndian::write_u16(&mut pongbuf[1..], sequence);
+    stream.write_all(pongbuf.as_ref())
+}
+
+/// Resolve `host:port` to the first IPv4 address it names.
+fn resolve(addr: &str) -> Option<SocketAddrV4> {
+    addr.to_socket_addrs().ok()?.filter_map(|addr| {
+        match addr {
+            SocketAddr::V4(addr) => Some(addr),
+            SocketAddr::V6(_) => None,
+        }
+    }).next()
+}
+
+/// Ask the server to also send the audio to `sink`, or with `add` false to stop doing so.
+pub fn send_sink(stream: &mut TcpStream, sink: SocketAddrV4, add: bool) -> io::Result<()> {
+    let mut sinkbuf = [0u8; 7];
+    sinkbuf[0] = if add { 8 } else { 9 };
+    BigEndian::write_u16(&mut sinkbuf[1..3], sink.port());
+    sinkbuf[3..].copy_from_slice(&sink.ip().octets());
+    debug!("{:?}", sinkbuf);
+    stream.write_all(sinkbuf.as_ref())
+}
+
+/// Ask the server to stop sending us audio.
+pub fn send_stop(stream: &mut TcpStream) -> io::Result<()> {
+    let stopbuf = [6u8, 0, 0];
+    stream.write_all(stopbuf.as_ref())
+}
+
+/// Tell the server we are leaving; it answers with a GOODBYE and closes the connection.
+pub fn send_quit(stream: &mut TcpStream) -> io::Result<()> {
+    let quitbuf = [7u8, 0, 0];
+    stream.write_all(quitbuf.as_ref())
+}
+
+/// Answer the server's nonce with an HMAC-SHA256 over it keyed with our secret.
+fn send_auth(stream: &mut TcpStream, credentials: &Credentials, nonce: &[u8]) -> io::Result<()> {
+    let mut authbuf = vec![10u8, credentials.key_name.len() as u8];
+    authbuf.extend_from_slice(credentials.key_name.as_bytes());
+    authbuf.extend_from_slice(&HMAC::mac(nonce, credentials.secret.as_bytes()));
+    stream.write_all(&authbuf)
+}
+
+/// Echo the cookie of a UDP challenge, proving we receive on the sink it was sent to.
+pub fn send_verify(stream: &mut TcpStream, cookie: u64) -> io::Result<()> {
+    let mut verifybuf = [0u8; 9];
+    verifybuf[0] = 11;
+    BigEndian::write_u64(&mut verifybuf[1..], cookie);
+    stream.write_all(verifybuf.as_ref())
+}
+
+/// Read a string prefixed by its one byte length, as used by ANNOUNCE and INVALID_COMMAND.
+fn read_string(stream: &mut TcpStream) -> io::Result<String> {
+    let mut size = [0u8; 1];
+    stream.read_exact(&mut size)?;
+    info!("{}", size[0]);
+    let mut string = vec![0u8; size[0] as usize];
+    stream.read_exact(&mut string)?;
+    Ok(String::from_utf8_lossy(&string).into_owned())
+}
+
+/// Read the next reply from the server, blocking until all of it has arrived.
+/// Read the next reply, whose format may depend on the `capabilities` agreed on in WELCOME.
+pub fn read_reply(stream: &mut TcpStream, capabilities: u16) -> io::Result<Reply> {
+    let mut reply_type_buf = [0u8; 1];
+    stream.read_exact(&mut reply_type_buf)?;
+
+    info!("{}", reply_type_buf[0]);
+    let reply = match reply_type_buf[0] {
+        0 => {
+            let mut num_stations = [0u8; 2];
+            stream.read_exact(&mut num_stations)?;
+            Reply::Welcome(Welcome {
+                num_stations: BigEndian::read_u16(&num_stations),
+                version: 0,
+                capabilities: 0,
+                nonce: None,
+            })
+        }
+        5 => {
+            let mut welcomebuf = [0u8; 5];
+            stream.read_exact(&mut welcomebuf)?;
+            let capabilities = BigEndian::read_u16(&welcomebuf[3..]);
+            let nonce = if capabilities & CAP_AUTH != 0 {
+                let mut nonce = [0u8; NONCE_LEN];
+                stream.read_exact(&mut nonce)?;
+                Some(nonce)
+            } else {
+                None
+            };
+            Reply::Welcome(Welcome {
+                num_stations: BigEndian::read_u16(&welcomebuf[..2]),
+                version: welcomebuf[2],
+                capabilities,
+                nonce,
+            })
+        }
+        1 => Reply::Announce { song_name: read_string(stream)? },
+        2 => Reply::InvalidCommand { reply_string: read_string(stream)? },
+        3 => {
+            let mut num_stations = [0u8; 2];
+            stream.read_exact(&mut num_stations)?;
+            let num_stations = BigEndian::read_u16(&num_stations);
+            let mut stations = Vec::with_capacity(num_stations as usize);
+            for _ in 0..num_stations {
+                let mut station_number = [0u8; 2];
+                stream.read_exact(&mut station_number)?;
+                let station_number = BigEndian::read_u16(&station_number);
+                let name = read_string(stream)?;
+                let song_name = read_string(stream)?;
+                let health = if capabilities & CAP_STATION_STATUS != 0 {
+                    let mut status = [0u8; 1];
+                    stream.read_exact(&mut status)?;
+                    Some(Health::from_byte(status[0]))
+                } else {
+                    None
+                };
+                stations.push(StationEntry {
+                    station_number,
+                    name,
+                    song_name,
+                    health,
+                });
+            }
+            Reply::StationList { stations }
+        }
+        4 => {
+            let mut station_number = [0u8; 2];
+            stream.read_exact(&mut station_number)?;
+            let song_name = read_string(stream)?;
+            let mut times = [0u8; 8];
+            stream.read_exact(&mut times)?;
+            Reply::NowPlaying {
+                station_number: BigEndian::read_u16(&station_number),
+                song_name,
+                elapsed_ms: BigEndian::read_u32(&times[..4]),
+                duration_ms: BigEndian::read_u32(&times[4..]),
+            }
+        }
+        6 => {
+            let mut sequence = [0u8; 2];
+            stream.read_exact(&mut sequence)?;
+            Reply::Ping { sequence: BigEndian::read_u16(&sequence) }
+        }
+        7 => {
+            let mut unused = [0u8; 2];
+            stream.read_exact(&mut unused)?;
+            Reply::Goodbye
+        }
+        8 => {
+            let mut statusbuf = [0u8; 3];
+            stream.read_exact(&mut statusbuf)?;
+            Reply::StationStatus {
+                station_number: BigEndian::read_u16(&statusbuf[..2]),
+                health: Health::from_byte(statusbuf[2]),
+            }
+        }
+        reply_type => Reply::Unknown { reply_type },
+    };
+
+    Ok(reply)
+}
+
+/// Read replies from `stream` on a separate thread and forward them to `tx`.
+///
+/// The thread sends `Event::Disconnected` and exits once the stream is closed, fails or has
+/// been shut down.
+pub fn spawn_reader(stream: &TcpStream,
+                    connection: usize,
+                    capabilities: u16,
+                    tx: Sender<Event>)
+                    -> io::Result<()> {
+    let mut stream = stream.try_clone()?;
+    thread::spawn(move || {
+        loop {
+            match read_reply(&mut stream, capabilities) {
+                Ok(reply) => {
+                    if tx.send(Event::Reply(connection, reply)).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    debug!("reader for connection {} done: {}", connection, e);
+                    tx.send(Event::Disconnected(connection)).ok();
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Format milliseconds as `m:ss`.
+fn format_ms(ms: u32) -> String {
+    let secs = ms / 1000;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Render how far into a song a station is, e.g. `[#####-----] 1:05 / 2:10`.
+fn progress_bar(elapsed_ms: u32, duration_ms: u32) -> String {
+    const WIDTH: u64 = 20;
+    let filled = if duration_ms == 0 {
+        0
+    } else {
+        cmp::min(WIDTH, elapsed_ms as u64 * WIDTH / duration_ms as u64)
+    };
+    let mut bar = String::with_capacity(WIDTH as usize + 2);
+    bar.push('[');
+    for i in 0..WIDTH {
+        bar.push(if i < filled { '#' } else { '-' });
+    }
+    bar.push(']');
+    format!("{} {} / {}", bar, format_ms(elapsed_ms), format_ms(duration_ms))
+}
+
+/// A control session with a rustcast server that survives dropped connections.
+///
+/// When the connection is lost the session reconnects with exponential backoff, replays HELLO
+/// and tunes back into the last station that was requested.
+pub struct Session {
+    servername: String,
+    serverport: u16,
+    udpport: u16,
+
+    // last station requested by the user, replayed after a reconnect
+    station: Option<u16>,
+
+    // last station the server confirmed with an ANNOUNCE
+    confirmed_station: Option<u16>,
+
+    // number of the current connection, used to tell apart events from previous ones
+    connection: usize,
+
+    // capabilities the server agreed to in its WELCOME
+    capabilities: u16,
+
+    // extra sinks added by the user, replayed after a reconnect
+    sinks: Vec<SocketAddrV4>,
+
+    // used to authenticate again after a reconnect
+    credentials: Option<Credentials>,
+}
+
+impl Session {
+    pub fn new(servername: &str,
+               serverport: u16,
+               udpport: u16,
+               credentials: Option<Credentials>)
+               -> Session {
+        Session {
+            servername: servername.to_string(),
+            serverport,
+            udpport,
+            station: None,
+            confirmed_station: None,
+            connection: 0,
+            capabilities: 0,
+            sinks: Vec::new(),
+            credentials,
+        }
+    }
+
+    /// Drive the session until `Event::Quit` arrives on `rx`.
+    ///
+    /// `first` is a connection on which the handshake has already been done, with the server's
+    /// answer to it; without one the session starts out reconnecting. `tx` is cloned for the
+    /// threads reading the server's replies.
+    pub fn run<W: Write>(&mut self,
+                         first: Option<(TcpStream, Welcome)>,
+                         tx: Sender<Event>,
+                         rx: Receiver<Event>,
+                         mut out: W) {
+        let (mut stream, mut connected) = match first {
+            Some((stream, welcome)) => {
+                self.capabilities = welcome.capabilities;
+                let reading =
+                    spawn_reader(&stream, self.connection, self.capabilities, tx.clone()).is_ok();
+                (stream, reading)
+            }
+            None => {
+                match self.reconnect(&tx, &rx, &mut out) {
+                    Some(stream) => (stream, true),
+                    None => return,
+                }
+            }
+        };
+
+        loop {
+            if connected {
+                let event = match rx.recv() {
+                    Ok(event) => event,
+                    Err(_) => return,
+                };
+
+                connected = match event {
+                    Event::Quit => {
+                        self.quit(&mut stream, &rx, &mut out);
+                        return;
+                    }
+                    Event::Stop => self.stop(&mut stream, &mut out),
+                    Event::AddSink(sink) => self.sink(&mut stream, sink, true, &mut out),
+                    Event::RemoveSink(sink) => self.sink(&mut stream, sink, false, &mut out),
+                    Event::Verify(cookie) => {
+                        if let Err(e) = send_verify(&mut stream, cookie) {
+                            warn!("Failed to send VERIFY: {}", e);
+                            false
+                        } else {
+                            true
+                        }
+                    }
+                    Event::SetStation(station) => self.set_station(&mut stream, station, &mut out),
+                    Event::ListStations => self.list_stations(&mut stream, &mut out),
+                    Event::NowPlaying(station) => {
+                        self.now_playing(&mut stream, station.or(self.confirmed_station), &mut out)
+                    }
+                    Event::Reply(connection, reply) if connection == self.connection => {
+                        self.handle_reply(&mut stream, reply, &mut out)
+                    }
+                    Event::Disconnected(connection) => connection != self.connection,
+                    Event::Reply(..) => true,
+                };
+                continue;
+            }
+
+            // make sure the reader of the old connection goes away
+            stream.shutdown(Shutdown::Both).ok();
+
+            writeln!(out, "Lost connection to the server.").unwrap();
+            stream = match self.reconnect(&tx, &rx, &mut out) {
+                Some(stream) => stream,
+                None => return,
+            };
+            connected = true;
+        }
+    }
+
+    /// Ask the server for `station`, returning whether the connection is still usable.
+    fn set_station<W: Write>(&mut self, stream: &mut TcpStream, station: u16, out: &mut W) -> bool {
+        self.station = Some(station);
+        if let Err(e) = send_set_station(stream, station) {
+            warn!("Failed to send SET_STATION: {}", e);
+            return false;
+        }
+
+        writeln!(out, "Waiting for an announce…").unwrap();
+        true
+    }
+
+    /// Whether the server agreed to `capability`, telling the user if it did not.
+    fn supports<W: Write>(&self, capability: u16, command: &str, out: &mut W) -> bool {
+        if self.capabilities & capability != 0 {
+            return true;
+        }
+
+        writeln!(out, "The server does not support {}", command).unwrap();
+        write!(out, "> ").unwrap();
+        out.flush().unwrap();
+        false
+    }
+
+    /// Stop the audio, returning whether the connection is still usable.
+    ///
+    /// The server does not answer a STOP. The station is forgotten, so it is not restored after
+    /// a reconnect either.
+    fn stop<W: Write>(&mut self, stream: &mut TcpStream, out: &mut W) -> bool {
+        if !self.supports(CAP_STOP_QUIT, "stop", out) {
+            return true;
+        }
+
+        self.station = None;
+        self.confirmed_station = None;
+        if let Err(e) = send_stop(stream) {
+            warn!("Failed to send STOP: {}", e);
+            return false;
+        }
+
+        writeln!(out, "Stopped; type in a number to listen again.").unwrap();
+        write!(out, "> ").unwrap();
+        out.flush().unwrap();
+        true
+    }
+
+    /// Add or remove an extra sink, returning whether the connection is still usable.
+    fn sink<W: Write>(&mut self,
+                      stream: &mut TcpStream,
+                      sink: SocketAddrV4,
+                      add: bool,
+                      out: &mut W)
+                      -> bool {
+        if !self.supports(CAP_SINKS, "sink", out) {
+            return true;
+        }
+
+        self.sinks.retain(|&s| s != sink);
+        if add {
+            self.sinks.push(sink);
+        }
+        if let Err(e) = send_sink(stream, sink, add) {
+            warn!("Failed to send sink change: {}", e);
+            return false;
+        }
+
+        if add {
+            writeln!(out, "Also sending the audio to {}", sink).unwrap();
+        } else {
+            writeln!(out, "No longer sending the audio to {}", sink).unwrap();
+        }
+        write!(out, "> ").unwrap();
+        out.flush().unwrap();
+        true
+    }
+
+    /// Say goodbye to the server if it supports it, waiting a moment for its GOODBYE.
+    fn quit<W: Write>(&mut self, stream: &mut TcpStream, rx: &Receiver<Event>, out: &mut W) {
+        if self.capabilities & CAP_STOP_QUIT == 0 || send_quit(stream).is_err() {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(GOODBYE_TIMEOUT_MS);
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(timeout) {
+                Ok(Event::Reply(connection, Reply::Goodbye)) if connection == self.connection => {
+                    writeln!(out, "Goodbye.").unwrap();
+                    return;
+                }
+                Ok(Event::Disconnected(connection)) if connection == self.connection => return,
+                Ok(_) => (),
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Ask the server for its stations, returning whether the connection is still usable.
+    fn list_stations<W: Write>(&mut self, stream: &mut TcpStream, out: &mut W) -> bool {
+        if !self.supports(CAP_LIST_STATIONS, "ls", out) {
+            return true;
+        }
+
+        if let Err(e) = send_list_stations(stream) {
+            warn!("Failed to send LIST_STATIONS: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Ask the server what `station` is playing, returning whether the connection is still
+    /// usable.
+    fn now_playing<W: Write>(&mut self,
+                             stream: &mut TcpStream,
+                             station: Option<u16>,
+                             out: &mut W)
+                             -> bool {
+        if !self.supports(CAP_NOW_PLAYING, "np", out) {
+            return true;
+        }
+
+        let station = match station {
+            Some(station) => station,
+            None => {
+                writeln!(out, "Not tuned into a station; use np <station>").unwrap();
+                write!(out, "> ").unwrap();
+                out.flush().unwrap();
+                return true;
+            }
+        };
+
+        if let Err(e) = send_now_playing(stream, station) {
+            warn!("Failed to send NOW_PLAYING: {}", e);
+            return false;
+        }
+        true
+    }
+
+    /// Report a reply from the server to `out`, or answer it if it was a PING. Returns whether
+    /// the connection is still usable.
+    fn handle_reply<W: Write>(&mut self,
+                              stream: &mut TcpStream,
+                              reply: Reply,
+                              out: &mut W)
+                              -> bool {
+        match reply {
+            Reply::Welcome(_) => {
+                error!("Server resent Welcome");
+                false
+            }
+            Reply::Announce { song_name } => {
+                debug!("Announce");
+                self.confirmed_station = self.station;
+
+                writeln!(out, "New song announced: {}", song_name).unwrap();
+                write!(out, "> ").unwrap();
+                out.flush().unwrap();
+                true
+            }
+            Reply::InvalidCommand { reply_string } => {
+                info!("{}", reply_string);
+
+                // don't replay the rejected station after reconnecting
+                self.station = self.confirmed_station;
+
+                writeln!(out, "INVALID_COMMAND_REPLY: {}", reply_string).unwrap();
+                writeln!(out, "Server has closed the connection.").unwrap();
+                false
+            }
+            Reply::StationList { stations } => {
+                for station in stations {
+                    let status = match station.health {
+                        Some(health) if health != Health::Online => format!(" [{}]", health.name()),
+                        _ => String::new(),
+                    };
+                    writeln!(out,
+                             "{:>3}: {} (now playing: {}){}",
+                             station.station_number,
+                             station.name,
+                             station.song_name,
+                             status)
+                        .unwrap();
+                }
+                write!(out, "> ").unwrap();
+                out.flush().unwrap();
+                true
+            }
+            Reply::NowPlaying { station_number, song_name, elapsed_ms, duration_ms } => {
+                writeln!(out,
+                         "Station {} is playing {} {}",
+                         station_number,
+                         song_name,
+                         progress_bar(elapsed_ms, duration_ms))
+                    .unwrap();
+                write!(out, "> ").unwrap();
+                out.flush().unwrap();
+                true
+            }
+            Reply::Ping { sequence } => {
+                trace!("PING {}", sequence);
+                send_pong(stream, sequence).is_ok()
+            }
+            Reply::StationStatus { station_number, health } => {
+                match health {
+                    Health::Online => {
+                        writeln!(out, "Station {} is back online.", station_number).unwrap()
+                    }
+                    Health::Fallback => {
+                        writeln!(out,
+                                 "Station {} lost its source; playing its fallback until it \
+                                  recovers.",
+                                 station_number)
+                            .unwrap()
+                    }
+                    Health::Offline => {
+                        writeln!(out,
+                                 "Station {} is offline; it will resume once its source \
+                                  recovers.",
+                                 station_number)
+                            .unwrap()
+                    }
+                }
+                write!(out, "> ").unwrap();
+                out.flush().unwrap();
+                true
+            }
+            Reply::Goodbye => {
+                writeln!(out, "Server said goodbye.").unwrap();
+                false
+            }
+            Reply::Unknown { reply_type } => {
+                error!("Server sent an unknown response: {}", reply_type);
+                false
+            }
+        }
+    }
+
+    /// Reconnect to the server, backing off between attempts, and restore the extra sinks and
+    /// the last station.
+    ///
+    /// Station changes requested while disconnected are remembered and applied once the
+    /// connection is back. Returns `None` if the user quits in the meantime.
+    fn reconnect<W: Write>(&mut self,
+                           tx: &Sender<Event>,
+                           rx: &Receiver<Event>,
+                           out: &mut W)
+                           -> Option<TcpStream> {
+        let mut backoff = INITIAL_BACKOFF_SECS;
+        loop {
+            writeln!(out, "Reconnecting in {}s…", backoff).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(backoff);
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                match rx.recv_timeout(timeout) {
+                    Ok(Event::SetStation(station)) => self.station = Some(station),
+                    Ok(Event::Stop) => {
+                        self.station = None;
+                        self.confirmed_station = None;
+                    }
+                    Ok(Event::AddSink(sink)) => {
+                        self.sinks.retain(|&s| s != sink);
+                        self.sinks.push(sink);
+                    }
+                    Ok(Event::RemoveSink(sink)) => self.sinks.retain(|&s| s != sink),
+                    Ok(Event::ListStations) |
+                    Ok(Event::NowPlaying(_)) => {
+                        writeln!(out, "Not connected; try again once reconnected.").unwrap();
+                    }
+                    Ok(Event::Quit) |
+                    Err(RecvTimeoutError::Disconnected) => return None,
+                    // leftovers from the connection we lost
+                    Ok(_) => (),
+                    Err(RecvTimeoutError::Timeout) => break,
+                }
+            }
+
+            let mut stream = match connect(&self.servername,
+                                           self.serverport,
+                                           self.udpport,
+                                           self.credentials.as_ref()) {
+                Ok((stream, welcome)) => {
+                    writeln!(out,
+                             "Reconnected; the server has {} stations.",
+                             welcome.num_stations)
+                        .unwrap();
+                    self.capabilities = welcome.capabilities;
+                    stream
+                }
+                Err(e) => {
+                    warn!("Reconnect failed: {}", e);
+                    backoff = cmp::min(backoff * 2, MAX_BACKOFF_SECS);
+                    continue;
+                }
+            };
+
+            self.connection += 1;
+            if let Err(e) = spawn_reader(&stream, self.connection, self.capabilities, tx.clone()) {
+                warn!("Failed to start reading replies: {}", e);
+                continue;
+            }
+
+            if self.capabilities & CAP_SINKS != 0 && !self.sinks.is_empty() {
+                writeln!(out, "Restoring {} extra sinks", self.sinks.len()).unwrap();
+                let restored = self.sinks.iter().all(|&sink| {
+                    send_sink(&mut stream, sink, true).is_ok()
+                });
+                if !restored {
+                    warn!("Failed to restore sinks");
+                    stream.shutdown(Shutdown::Both).ok();
+                    continue;
+                }
+            }
+
+            if let Some(station) = self.station {
+                writeln!(out, "Restoring station {}", station).unwrap();
+                if let Err(e) = send_set_station(&mut stream, station) {
+                    warn!("Failed to restore station: {}", e);
+                    stream.shutdown(Shutdown::Both).ok();
+                    continue;
+                }
+            }
+
+            return Some(stream);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use json;
+
+// set once the event log is written to stdout, which the lifecycle messages then have to avoid
+static OWNS_STDOUT: AtomicBool = AtomicBool::new(false);
+
+/// Print a lifecycle message for whoever runs the server, like `println!`, but to stderr while
+/// stdout carries the event log.
+macro_rules! console {
+    ($($arg:tt)*) => {
+        if ::eventlog::owns_stdout() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub fn owns_stdout() -> bool {
+    OWNS_STDOUT.load(Ordering::Relaxed)
+}
+
+/// A stream of server events written as JSON lines.
+///
+/// Every event carries its type, a timestamp and the token, peer address and station it is about,
+/// `null` where they do not apply, followed by fields specific to the type.
+pub struct EventLog {
+    out: Box<dyn Write>,
+}
+
+impl EventLog {
+    /// Append the events to the file at `path`, or write them to stdout if it is `-`.
+    pub fn open(path: &str) -> io::Result<EventLog> {
+        let out: Box<dyn Write> = if path == "-" {
+            OWNS_STDOUT.store(true, Ordering::Relaxed);
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        Ok(EventLog { out })
+    }
+
+    /// Write an event of type `event`.
+    ///
+    /// `fields` hold the name of each extra field along with its value, already encoded as JSON.
+    pub fn write(&mut self,
+                 event: &str,
+                 token: Option<usize>,
+                 peer: Option<Ipv4Addr>,
+                 station: Option<u16>,
+                 fields: &[(&str, String)]) {
+        let null = || "null".to_string();
+        let mut line = format!("{{\"ts\":\"{}\",\"event\":{},\"token\":{},\"peer\":{},\
+                                \"station\":{}",
+                               timestamp(),
+                               json::quote(event),
+                               token.map_or_else(null, |token| token.to_string()),
+                               peer.map_or_else(null, |peer| json::quote(&peer.to_string())),
+                               station.map_or_else(null, |station| station.to_string()));
+        for &(name, ref value) in fields {
+            line.push(',');
+            line.push_str(&json::quote(name));
+            line.push(':');
+            line.push_str(value);
+        }
+        line.push('}');
+
+        if let Err(e) = writeln!(self.out, "{}", line).and_then(|_| self.out.flush()) {
+            warn!("Failed to write to the event log: {}", e);
+        }
+    }
+}
+
+/// The current time in RFC 3339 format, in UTC with millisecond precision.
+fn timestamp() -> String {
+    format_time(SystemTime::now())
+}
+
+/// `time` in RFC 3339 format, in UTC with millisecond precision.
+pub fn format_time(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            now.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn format_time_writes_utc_with_milliseconds() {
+        let cases = [(0, 0, "1970-01-01T00:00:00.000Z"),
+                     (946_684_799, 999, "1999-12-31T23:59:59.999Z"),
+                     (951_782_400, 0, "2000-02-29T00:00:00.000Z"),
+                     (1_709_251_199, 42, "2024-02-29T23:59:59.042Z"),
+                     (4_107_587_696, 7, "2100-03-01T12:34:56.007Z")];
+        for &(secs, millis, formatted) in &cases {
+            let time = UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_millis(millis);
+            assert_eq!(format_time(time), formatted);
+        }
+    }
+
+    #[test]
+    fn format_time_takes_times_before_the_epoch_as_the_epoch() {
+        let time = UNIX_EPOCH - Duration::from_secs(60);
+        assert_eq!(format_time(time), "1970-01-01T00:00:00.000Z");
+    }
+}
@@ -0,0 +1,23 @@
+use byteorder::{ByteOrder, BigEndian};
+use std::io::{self, Write};
+use commands::CHALLENGE_MAGIC;
+use std::net::UdpSocket;
+
+/// Receive the station's audio stream on `socket` and copy every datagram to `out`.
+///
+/// UDP challenges are handed to `on_challenge` instead of being copied. Only returns when
+/// receiving or writing fails, e.g. when the player reading `out` has exited.
+pub fn receive_loop<W: Write, F: FnMut(u64)>(socket: &UdpSocket,
+                                             mut out: W,
+                                             mut on_challenge: F)
+                                             -> io::Result<()> {
+    loop {
+        let mut buf = [0u8; 2048]; // unsure if this should match the server buffer size
+        let (amt, _) = socket.recv_from(&mut buf)?;
+        if amt == CHALLENGE_MAGIC.len() + 8 && buf.starts_with(CHALLENGE_MAGIC) {
+            on_challenge(BigEndian::read_u64(&buf[CHALLENGE_MAGIC.len()..amt]));
+            continue;
+        }
+        out.write_all(&buf[0..amt])?;
+    }
+}
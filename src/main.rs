@@ -1,5 +1,7 @@
 extern crate byteorder;
 extern crate clap;
+extern crate hmac_sha256;
+extern crate libc;
 extern crate mio;
 extern crate slab;
 
@@ -7,18 +9,45 @@ extern crate slab;
 extern crate log;
 extern crate env_logger;
 
+#[macro_use]
+mod eventlog;
+mod acl;
+mod archive;
+mod auth;
 mod commands;
+mod ingest;
 mod server;
 mod connection;
+mod json;
+mod metrics;
+mod ratelimit;
+mod relay;
+mod schedule;
+mod silence;
+mod station;
+mod timeshift;
 
 use clap::{App, Arg};
 use mio::*;
-use mio::tcp::*;
+use mio::net::TcpListener;
+use acl::AccessList;
+use eventlog::EventLog;
 use server::*;
+use silence::Silence;
+use station::{JingleInterval, Jingles, SourceSpec, StationConfig, BYTES_PER_SEC};
+use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// longest interval between keepalive PINGs, so that clients are not kept around forever
+const MAX_PING_INTERVAL_SECS: u64 = 60 * 60;
+
+// most audio stations keep for new listeners, which they hold in memory
+const MAX_TIME_SHIFT_SECS: u64 = 30;
 
 fn main() {
-    env_logger::init().ok().expect("Failed to initialize logger");
+    env_logger::init().expect("Failed to initialize logger");
 
     let matches = App::new("rustcast_server")
         .version("0.1.0")
@@ -26,26 +55,242 @@ fn main() {
         .arg(Arg::with_name("file1")
             .required(true)
             .index(2)
-            .help("e.g.: ../mp3/U2-StuckInAMoment.mp3 OR ../mp3/* (to glob)")
+            .help("e.g.: ../mp3/U2-StuckInAMoment.mp3 OR ../mp3/* (to glob); 'silence', \
+                   'live', 'station:<number>', 'relay:<host>:<port>/<station>' or \
+                   'schedule:<file>' also make a station")
             .multiple(true))
+        .arg(Arg::with_name("ping-interval")
+            .long("ping-interval")
+            .takes_value(true)
+            .default_value("15")
+            .help("seconds between keepalive PINGs, from 1 to 3600; clients missing 3 in a row \
+                   are dropped, while clients without keepalives are only dropped once TCP \
+                   keepalives, sent after as many seconds without traffic, go unanswered"))
+        .arg(Arg::with_name("max-listeners")
+            .long("max-listeners")
+            .takes_value(true)
+            .help("most clients each station streams to at once; unlimited if not given"))
+        .arg(Arg::with_name("max-connections-per-ip")
+            .long("max-connections-per-ip")
+            .takes_value(true)
+            .help("most control connections one IP address may have open; unlimited if not \
+                   given"))
+        .arg(Arg::with_name("auth-keys")
+            .long("auth-keys")
+            .takes_value(true)
+            .help("file of keys clients can authenticate with, one '<name> <secret> \
+                   [<station>,...]' per line"))
+        .arg(Arg::with_name("restricted-stations")
+            .long("restricted-stations")
+            .takes_value(true)
+            .requires("auth-keys")
+            .help("comma separated stations only authenticated clients may listen to, e.g. 0,2"))
+        .arg(Arg::with_name("udp-challenge")
+            .long("udp-challenge")
+            .help("only send audio to UDP sinks once the client echoes a cookie sent to them; \
+                   sinks at other addresses than the client's always have to. Cookies are sent \
+                   up to 5 times, a second apart, before the client is disconnected"))
+        .arg(Arg::with_name("max-destination-rate")
+            .long("max-destination-rate")
+            .takes_value(true)
+            .help("most KiB per second all stations together send to any one host; unlimited \
+                   if not given"))
+        .arg(Arg::with_name("acl")
+            .long("acl")
+            .takes_value(true)
+            .help("file of 'allow|deny <cidr> [<station>,...]' rules for who may connect and \
+                   listen to which stations; reloaded on SIGHUP, closing the connections the \
+                   new rules deny"))
+        .arg(Arg::with_name("metrics-port")
+            .long("metrics-port")
+            .takes_value(true)
+            .help("serve Prometheus metrics over HTTP on this port, at /metrics"))
+        .arg(Arg::with_name("fallback")
+            .long("fallback")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("<station>=<source> to play while the station's file fails, where the source \
+                   is a file, 'silence', 'station:<number>' or \
+                   'relay:<host>:<port>/<station>'; may be given once per station"))
+        .arg(Arg::with_name("jingles")
+            .long("jingles")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("<station>=<every>:<file>[,<file>...] to play the clips in turn between the \
+                   files the station plays, every <every> files or, as <minutes>m, every so many \
+                   minutes; may be given once per station"))
+        .arg(Arg::with_name("hide-jingles")
+            .long("hide-jingles")
+            .takes_value(true)
+            .help("comma separated stations whose jingles listeners are not announced"))
+        .arg(Arg::with_name("archive")
+            .long("archive")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("<station>=<directory> to record everything the station sends to, in a file \
+                   per hour along with a JSON line for every song change; may be given once per \
+                   station"))
+        .arg(Arg::with_name("silence-bitrate")
+            .long("silence-bitrate")
+            .takes_value(true)
+            .default_value("128")
+            .help("kbit/s of the silent MP3 frames stations play while they have nothing else \
+                   to; 32 to 128"))
+        .arg(Arg::with_name("time-shift")
+            .long("time-shift")
+            .takes_value(true)
+            .help("seconds of audio, up to 30, stations keep to send listeners that tune in at \
+                   once, so that their players start right away; it goes to sinks that \
+                   answered a UDP challenge and, without --udp-challenge, to those at the \
+                   client's own address"))
+        .arg(Arg::with_name("ingest-port")
+            .long("ingest-port")
+            .takes_value(true)
+            .requires("auth-keys")
+            .help("accept encoders pushing live audio to 'live' stations on this port, \
+                   Icecast style, authenticating with a key's name and secret"))
+        .arg(Arg::with_name("event-log")
+            .long("event-log")
+            .takes_value(true)
+            .help("append JSON lines describing server events to this file, or write them to \
+                   stdout if it is -"))
         .get_matches();
 
-    let mut stations: Vec<String> = vec![];
+    let mut stations: Vec<StationConfig> = vec![];
     if let Some(files) = matches.values_of("file1") {
         for file in files {
-            stations.push(file.parse::<String>().unwrap());
+            stations.push(StationConfig {
+                primary: SourceSpec::parse(file)
+                    .unwrap_or_else(|| panic!("Failed to parse station: {}", file)),
+                fallback: None,
+                jingles: None,
+                archive: None,
+            });
+        }
+    }
+    for (number, station) in stations.iter().enumerate() {
+        match station.primary {
+            SourceSpec::Station(other) if other as usize >= stations.len() ||
+                                          other as usize == number => {
+                panic!("Station {} cannot play station {}", number, other);
+            }
+            SourceSpec::Schedule(ref path) => {
+                if let Err(e) = schedule::load(path) {
+                    panic!("Failed to load schedule: {}", e);
+                }
+            }
+            _ => (),
+        }
+    }
+    if let Some(fallbacks) = matches.values_of("fallback") {
+        for fallback in fallbacks {
+            let (station, source) = parse_fallback(fallback, stations.len())
+                .unwrap_or_else(|| panic!("Failed to parse fallback: {}", fallback));
+            stations[station].fallback = Some(source);
+        }
+    }
+    if let Some(cycle) = station::find_cycle(&stations) {
+        let cycle: Vec<String> = cycle.iter().map(|station| station.to_string()).collect();
+        panic!("Stations play each other in a cycle: {}", cycle.join(" -> "));
+    }
+    if let Some(jingles) = matches.values_of("jingles") {
+        for jingle in jingles {
+            let (station, jingles) = parse_jingles(jingle, stations.len())
+                .unwrap_or_else(|| panic!("Failed to parse jingles: {}", jingle));
+            if let Some(missing) = jingles.files.iter().find(|file| !file.is_file()) {
+                panic!("Jingle {} is not a file", missing.display());
+            }
+            stations[station].jingles = Some(jingles);
+        }
+    }
+    if let Some(hidden) = matches.value_of("hide-jingles") {
+        for station in auth::parse_stations(hidden).expect("Failed to parse hidden jingles") {
+            match stations.get_mut(station as usize).and_then(|station| station.jingles.as_mut()) {
+                Some(jingles) => jingles.announce = false,
+                None => panic!("Station {} has no jingles to hide", station),
+            }
+        }
+    }
+    if let Some(archives) = matches.values_of("archive") {
+        for archive in archives {
+            let (station, dir) = archive.split_once('=')
+                .and_then(|(station, dir)| Some((station.parse::<usize>().ok()?, dir)))
+                .filter(|&(station, _)| station < stations.len())
+                .unwrap_or_else(|| panic!("Failed to parse archive: {}", archive));
+            fs::create_dir_all(dir).expect("Failed to create archive directory");
+            stations[station].archive = Some(PathBuf::from(dir));
         }
     }
-    debug!("{:?}", stations);
 
     let serverport = matches.value_of("tcpport").unwrap();
     debug!("server port: {}", serverport);
 
     let addr = ("0.0.0.0:".to_string() + serverport)
         .parse::<SocketAddr>()
-        .ok()
         .expect("Failed to parse host:port string");
-    let sock = TcpListener::bind(&addr).ok().expect("Failed to bind address");
+    let sock = TcpListener::bind(&addr).expect("Failed to bind address");
+
+    let ping_interval = matches.value_of("ping-interval")
+        .unwrap()
+        .parse::<u64>()
+        .ok()
+        .filter(|&secs| secs > 0 && secs <= MAX_PING_INTERVAL_SECS)
+        .expect("Failed to parse ping interval");
+    let max_listeners = matches.value_of("max-listeners")
+        .map(|max| max.parse::<usize>().expect("Failed to parse max listeners"));
+    let max_connections_per_ip = matches.value_of("max-connections-per-ip")
+        .map(|max| max.parse::<usize>().expect("Failed to parse max connections per IP"));
+    let auth_keys = matches.value_of("auth-keys")
+        .map(|path| auth::load_keys(path).expect("Failed to load auth keys"))
+        .unwrap_or_default();
+    let restricted_stations = matches.value_of("restricted-stations")
+        .map(|list| auth::parse_stations(list).expect("Failed to parse restricted stations"))
+        .unwrap_or_default();
+    let max_destination_rate = matches.value_of("max-destination-rate").map(|rate| {
+        rate.parse::<u64>()
+            .ok()
+            .and_then(|kib| kib.checked_mul(1024))
+            .expect("Failed to parse max destination rate")
+    });
+    let access_list_path = matches.value_of("acl").map(|path| path.to_string());
+    let access_list = match access_list_path {
+        Some(ref path) => {
+            acl::reload_on_sighup();
+            AccessList::load(path).expect("Failed to load access list")
+        }
+        None => AccessList::default(),
+    };
+    let silence_bitrate = matches.value_of("silence-bitrate")
+        .unwrap()
+        .parse::<u32>()
+        .ok()
+        .filter(|&kbps| {
+            Silence::new(kbps).is_some_and(|silence| silence.bytes_per_sec() <= BYTES_PER_SEC)
+        })
+        .expect("Failed to parse silence bitrate");
+    let time_shift = matches.value_of("time-shift").map(|secs| {
+        secs.parse::<u64>()
+            .ok()
+            .filter(|&secs| secs <= MAX_TIME_SHIFT_SECS)
+            .map(Duration::from_secs)
+            .expect("Failed to parse time shift")
+    });
+    let settings = Settings {
+        ping_interval: Duration::from_secs(ping_interval),
+        max_listeners,
+        max_connections_per_ip,
+        auth_keys,
+        restricted_stations,
+        udp_challenge: matches.is_present("udp-challenge"),
+        max_destination_rate,
+        access_list,
+        access_list_path,
+        silence_bitrate,
+        time_shift,
+    };
 
     // Create a polling object that will be used by the server to receive events
     let mut poll = Poll::new().expect("Failed to create Poll");
@@ -54,6 +299,63 @@ fn main() {
     // the details of how registering works inside of the `Server` object. One reason I
     // really like this is to get around having to have `const SERVER = Token(0)` at the top of my
     // file. It also keeps our polling options inside `Server`.
-    let mut server = Server::new(sock, stations);
+    let mut server = Server::new(sock, stations, settings);
+    if let Some(path) = matches.value_of("event-log") {
+        server.log_events(EventLog::open(path).expect("Failed to open event log"));
+    }
+    if let Some(metrics_port) = matches.value_of("metrics-port") {
+        let metrics_addr = ("0.0.0.0:".to_string() + metrics_port)
+            .parse::<SocketAddr>()
+            .expect("Failed to parse metrics port");
+        let metrics_sock = TcpListener::bind(&metrics_addr).expect("Failed to bind metrics port");
+        server.serve_metrics(metrics_sock);
+    }
+    if let Some(ingest_port) = matches.value_of("ingest-port") {
+        let ingest_addr = ("0.0.0.0:".to_string() + ingest_port)
+            .parse::<SocketAddr>()
+            .expect("Failed to parse ingest port");
+        let ingest_sock = std::net::TcpListener::bind(ingest_addr)
+            .expect("Failed to bind ingest port");
+        server.serve_ingest(ingest_sock);
+    }
     server.run(&mut poll).expect("Failed to run server");
 }
+
+/// Parse `<station>=<source>`, checking that both stations exist among `num_stations` and are not
+/// the same, and that the source is not live.
+fn parse_fallback(s: &str, num_stations: usize) -> Option<(usize, SourceSpec)> {
+    let split = s.find('=')?;
+    let station = s[..split].parse::<usize>().ok().filter(|&n| n < num_stations)?;
+    let source = SourceSpec::parse(&s[split + 1..])?;
+    match source {
+        SourceSpec::Station(other) if other as usize >= num_stations ||
+                                      other as usize == station => return None,
+        // live audio only replaces a station's primary source
+        SourceSpec::Live => return None,
+        _ => (),
+    }
+    Some((station, source))
+}
+
+/// Parse `<station>=<every>:<file>[,<file>...]`, where `<every>` is a number of files or
+/// `<minutes>m`, checking that the station exists among `num_stations`.
+fn parse_jingles(s: &str, num_stations: usize) -> Option<(usize, Jingles)> {
+    let (station, jingles) = s.split_once('=')?;
+    let station = station.parse::<usize>().ok().filter(|&n| n < num_stations)?;
+    let (every, files) = jingles.split_once(':')?;
+    let every = match every.strip_suffix('m') {
+        Some(minutes) => {
+            let minutes = minutes.parse::<u64>().ok().filter(|&m| m > 0)?;
+            // the interval is kept in seconds
+            minutes.checked_mul(60)?;
+            JingleInterval::Minutes(minutes)
+        },
+        None => JingleInterval::Files(every.parse().ok().filter(|&n| n > 0)?),
+    };
+    let jingles = Jingles {
+        files: files.split(',').map(PathBuf::from).collect(),
+        every,
+        announce: true,
+    };
+    Some((station, jingles))
+}
@@ -0,0 +1,409 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as FmtWrite;
+use std::io::{self, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use mio::*;
+use mio::net::{TcpListener, TcpStream};
+
+// requests are answered once their headers are in, or refused if they do not fit in this much
+const MAX_REQUEST_SIZE: usize = 4096;
+
+// scrapers still connected this long after connecting are closed, whether they are done or not
+const CLIENT_TIMEOUT_SECS: u64 = 10;
+
+// most scrapers connected at once; more are turned away
+const MAX_CONNECTIONS: usize = 16;
+
+// tokens of HTTP clients are handed out from here on, clear of the control connections
+const FIRST_CLIENT_TOKEN: usize = 20_000_000;
+
+/// Counters a station thread keeps about what it sent, read when the metrics are scraped.
+#[derive(Default)]
+pub struct StationMetrics {
+    pub bytes_sent: AtomicU64,
+    pub packets_sent: AtomicU64,
+    pub send_errors: AtomicU64,
+
+    // how much later than PACKET_INTERVAL_NS after the previous packet packets went out, summed
+    pub pacing_lag_ns: AtomicU64,
+}
+
+/// Counters kept by the server's event loop.
+#[derive(Default)]
+pub struct Metrics {
+    pub connections_accepted: u64,
+    pub handshakes: u64,
+    pub keepalive_timeouts: u64,
+
+    // INVALID_COMMANDs sent, by reason
+    invalid_commands: BTreeMap<&'static str, u64>,
+}
+
+/// What is exposed about a station on top of its `StationMetrics`.
+pub struct StationSample<'a> {
+    pub name: &'a str,
+    pub listeners: usize,
+    pub metrics: &'a StationMetrics,
+}
+
+impl Metrics {
+    pub fn count_invalid_command(&mut self, reason: &'static str) {
+        *self.invalid_commands.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self, connected_clients: usize, stations: &[StationSample]) -> String {
+        let mut out = String::new();
+
+        header(&mut out, "rustcast_connected_clients", "gauge", "Open control connections.");
+        writeln!(out, "rustcast_connected_clients {}", connected_clients).unwrap();
+        header(&mut out,
+               "rustcast_connections_accepted_total",
+               "counter",
+               "Control connections accepted.");
+        writeln!(out,
+                 "rustcast_connections_accepted_total {}",
+                 self.connections_accepted)
+            .unwrap();
+        header(&mut out,
+               "rustcast_handshakes_total",
+               "counter",
+               "HELLOs answered with a WELCOME.");
+        writeln!(out, "rustcast_handshakes_total {}", self.handshakes).unwrap();
+        header(&mut out,
+               "rustcast_keepalive_timeouts_total",
+               "counter",
+               "Connections closed for not answering PINGs.");
+        writeln!(out,
+                 "rustcast_keepalive_timeouts_total {}",
+                 self.keepalive_timeouts)
+            .unwrap();
+        header(&mut out,
+               "rustcast_invalid_commands_total",
+               "counter",
+               "INVALID_COMMAND replies sent, by reason.");
+        for (reason, count) in &self.invalid_commands {
+            writeln!(out,
+                     "rustcast_invalid_commands_total{{reason=\"{}\"}} {}",
+                     reason,
+                     count)
+                .unwrap();
+        }
+
+        station_metric(&mut out,
+                       "rustcast_station_listeners",
+                       "gauge",
+                       "Clients listening to the station.",
+                       stations,
+                       |s| s.listeners.to_string());
+        station_metric(&mut out,
+                       "rustcast_station_bytes_sent_total",
+                       "counter",
+                       "Audio bytes sent over UDP.",
+                       stations,
+                       |s| s.metrics.bytes_sent.load(Ordering::Relaxed).to_string());
+        station_metric(&mut out,
+                       "rustcast_station_packets_sent_total",
+                       "counter",
+                       "Audio datagrams sent.",
+                       stations,
+                       |s| s.metrics.packets_sent.load(Ordering::Relaxed).to_string());
+        station_metric(&mut out,
+                       "rustcast_station_udp_send_errors_total",
+                       "counter",
+                       "Audio datagrams that failed to send.",
+                       stations,
+                       |s| s.metrics.send_errors.load(Ordering::Relaxed).to_string());
+        station_metric(&mut out,
+                       "rustcast_station_pacing_lag_seconds_total",
+                       "counter",
+                       "Time packets went out later than planned, summed.",
+                       stations,
+                       |s| {
+                           let lag_ns = s.metrics.pacing_lag_ns.load(Ordering::Relaxed);
+                           format!("{:.6}", lag_ns as f64 / 1e9)
+                       });
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Write a metric with one sample per station, labelled with its number and name.
+fn station_metric<F: Fn(&StationSample) -> String>(out: &mut String,
+                                                   name: &str,
+                                                   kind: &str,
+                                                   help: &str,
+                                                   stations: &[StationSample],
+                                                   value: F) {
+    header(out, name, kind, help);
+    for (number, station) in stations.iter().enumerate() {
+        writeln!(out,
+                 "{}{{station=\"{}\",name=\"{}\"}} {}",
+                 name,
+                 number,
+                 escape_label(station.name),
+                 value(station))
+            .unwrap();
+    }
+}
+
+/// Escape a label value as the exposition format requires.
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A scraper connected to the metrics endpoint.
+struct HttpClient {
+    sock: TcpStream,
+    request: Vec<u8>,
+    connected: Instant,
+
+    // the response and how much of it was written, once the request is in
+    response: Option<(Vec<u8>, usize)>,
+}
+
+/// The HTTP listener serving `/metrics`, driven by the server's event loop.
+pub struct MetricsListener {
+    sock: TcpListener,
+    pub token: Token,
+    clients: HashMap<Token, HttpClient>,
+    next_client: usize,
+}
+
+impl MetricsListener {
+    pub fn new(sock: TcpListener, token: Token) -> MetricsListener {
+        MetricsListener {
+            sock,
+            token,
+            clients: HashMap::new(),
+            next_client: FIRST_CLIENT_TOKEN,
+        }
+    }
+
+    pub fn register(&self, poll: &mut Poll) -> io::Result<()> {
+        poll.register(&self.sock, self.token, Ready::readable(), PollOpt::edge())
+    }
+
+    /// Whether `token` belongs to the listener or one of its clients.
+    pub fn owns(&self, token: Token) -> bool {
+        token == self.token || self.clients.contains_key(&token)
+    }
+
+    /// Accept every pending scraper, turning them away once `MAX_CONNECTIONS` are connected.
+    pub fn accept(&mut self, poll: &mut Poll) {
+        loop {
+            let (mut sock, peer) = match self.sock.accept() {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    if e.kind() != ErrorKind::WouldBlock {
+                        error!("Failed to accept metrics client, {:?}", e);
+                    }
+                    return;
+                }
+            };
+            if self.clients.len() >= MAX_CONNECTIONS {
+                console!("metrics {}: too many connections", peer);
+                // the response fits in the socket's buffer, and is not retried if it does not
+                sock.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\
+                                 Connection: close\r\n\r\n")
+                    .ok();
+                continue;
+            }
+            let token = Token(self.next_client);
+            self.next_client = self.next_client.checked_add(1).unwrap_or(FIRST_CLIENT_TOKEN);
+            match poll.register(&sock,
+                                token,
+                                Ready::readable() | Ready::writable(),
+                                PollOpt::edge()) {
+                Ok(_) => {
+                    self.clients.insert(token,
+                                        HttpClient {
+                                            sock,
+                                            request: Vec::new(),
+                                            connected: Instant::now(),
+                                            response: None,
+                                        });
+                }
+                Err(e) => error!("Failed to register metrics client, {:?}", e),
+            }
+        }
+    }
+
+    /// Read what `token` sent, returning whether its request is complete and still needs an
+    /// answer.
+    pub fn readable(&mut self, token: Token) -> bool {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return false,
+        };
+        if client.response.is_some() {
+            return false;
+        }
+
+        let mut buf = [0u8; 512];
+        while client.request.len() < MAX_REQUEST_SIZE {
+            let len = cmp::min(buf.len(), MAX_REQUEST_SIZE - client.request.len());
+            match client.sock.read(&mut buf[..len]) {
+                Ok(0) => {
+                    self.clients.remove(&token);
+                    return false;
+                }
+                Ok(n) => client.request.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("metrics client {:?} failed: {:?}", token, e);
+                    self.clients.remove(&token);
+                    return false;
+                }
+            }
+        }
+
+        client.request.windows(4).any(|w| w == b"\r\n\r\n") ||
+        client.request.len() >= MAX_REQUEST_SIZE
+    }
+
+    /// Answer the request of `token`, rendering the metrics with `render` if it asked for them.
+    pub fn respond<F: FnOnce() -> String>(&mut self, token: Token, render: F) {
+        let client = match self.clients.get_mut(&token) {
+            Some(client) => client,
+            None => return,
+        };
+
+        let request_line = client.request.split(|&b| b == b'\r').next().unwrap_or(&[]);
+        let mut words = request_line.split(|&b| b == b' ');
+        let (status, body) = match (words.next(), words.next()) {
+            (Some(b"GET"), Some(b"/metrics")) => ("200 OK", render()),
+            (Some(b"GET"), Some(_)) => ("404 Not Found", "not found\n".to_string()),
+            _ => ("400 Bad Request", "bad request\n".to_string()),
+        };
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                    Content-Length: {}\r\nConnection: close\r\n\r\n",
+                                   status,
+                                   body.len())
+            .into_bytes();
+        response.extend_from_slice(body.as_bytes());
+        client.response = Some((response, 0));
+        self.writable(token);
+    }
+
+    /// Close the scrapers that have been connected for too long, such as those that never finish
+    /// their request or stop reading the response.
+    pub fn close_idle(&mut self) {
+        let timeout = Duration::from_secs(CLIENT_TIMEOUT_SECS);
+        self.clients.retain(|token, client| {
+            let keep = client.connected.elapsed() < timeout;
+            if !keep {
+                debug!("metrics client {:?} timed out", token);
+            }
+            keep
+        });
+    }
+
+    /// Write as much of the response to `token` as its socket takes, closing it once done.
+    pub fn writable(&mut self, token: Token) {
+        let done = match self.clients.get_mut(&token) {
+            Some(&mut HttpClient { ref mut sock, response: Some((ref response, ref mut written)),
+                                   .. }) => {
+                loop {
+                    if *written == response.len() {
+                        break true;
+                    }
+                    match sock.write(&response[*written..]) {
+                        Ok(n) => *written += n,
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break false,
+                        Err(e) => {
+                            debug!("metrics client {:?} failed: {:?}", token, e);
+                            break true;
+                        }
+                    }
+                }
+            }
+            _ => false,
+        };
+        if done {
+            self.clients.remove(&token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_escape_backslashes_quotes_and_newlines() {
+        let cases = [("jazz", "jazz"),
+                     ("", ""),
+                     ("say \"hi\"", "say \\\"hi\\\""),
+                     ("C:\\music", "C:\\\\music"),
+                     ("two\nlines", "two\\nlines")];
+        for &(value, escaped) in &cases {
+            assert_eq!(escape_label(value), escaped);
+        }
+    }
+
+    #[test]
+    fn render_writes_every_counter_and_a_sample_per_station() {
+        let mut metrics = Metrics {
+            connections_accepted: 5,
+            handshakes: 4,
+            keepalive_timeouts: 1,
+            ..Metrics::default()
+        };
+        metrics.count_invalid_command("invalid_station");
+        metrics.count_invalid_command("access_denied");
+        metrics.count_invalid_command("invalid_station");
+        let jazz = StationMetrics::default();
+        jazz.bytes_sent.store(4096, Ordering::Relaxed);
+        jazz.packets_sent.store(4, Ordering::Relaxed);
+        jazz.pacing_lag_ns.store(1_500_000, Ordering::Relaxed);
+        let news = StationMetrics::default();
+        news.send_errors.store(2, Ordering::Relaxed);
+        let stations = [StationSample {
+                            name: "jazz",
+                            listeners: 3,
+                            metrics: &jazz,
+                        },
+                        StationSample {
+                            name: "\"news\"",
+                            listeners: 0,
+                            metrics: &news,
+                        }];
+
+        let out = metrics.render(2, &stations);
+        let expected = ["# HELP rustcast_connected_clients Open control connections.",
+                        "# TYPE rustcast_connected_clients gauge",
+                        "rustcast_connected_clients 2",
+                        "# TYPE rustcast_connections_accepted_total counter",
+                        "rustcast_connections_accepted_total 5",
+                        "rustcast_handshakes_total 4",
+                        "rustcast_keepalive_timeouts_total 1",
+                        // sorted by reason
+                        "rustcast_invalid_commands_total{reason=\"access_denied\"} 1\n\
+                         rustcast_invalid_commands_total{reason=\"invalid_station\"} 2",
+                        "# TYPE rustcast_station_listeners gauge",
+                        "rustcast_station_listeners{station=\"0\",name=\"jazz\"} 3",
+                        "rustcast_station_listeners{station=\"1\",name=\"\\\"news\\\"\"} 0",
+                        "rustcast_station_bytes_sent_total{station=\"0\",name=\"jazz\"} 4096",
+                        "rustcast_station_packets_sent_total{station=\"0\",name=\"jazz\"} 4",
+                        "rustcast_station_udp_send_errors_total{station=\"1\",\
+                         name=\"\\\"news\\\"\"} 2",
+                        "rustcast_station_pacing_lag_seconds_total{station=\"0\",name=\"jazz\"} \
+                         0.001500"];
+        for line in &expected {
+            assert!(out.contains(&format!("{}\n", line)), "{} missing from\n{}", line, out);
+        }
+        // every sample line is a name, optional labels and a value
+        for line in out.lines().filter(|line| !line.starts_with('#')) {
+            assert_eq!(line.rsplitn(2, ' ').count(), 2, "{}", line);
+        }
+    }
+}
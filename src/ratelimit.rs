@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+// buckets are only pruned once there are this many of them
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Caps the bytes per second the stations send to any one destination host, however many
+/// sessions and stations target it.
+///
+/// Every host gets a token bucket holding up to a second's worth of bytes.
+pub struct RateLimiter {
+    // bytes per second a single host may be sent
+    limit: u64,
+
+    // bytes each host may still be sent, and when that was last worked out
+    buckets: HashMap<Ipv4Addr, (u64, Instant)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u64) -> RateLimiter {
+        RateLimiter {
+            limit,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take `len` bytes from the bucket of `addr`, returning false if it does not hold that many.
+    pub fn allow(&mut self, addr: Ipv4Addr, len: usize) -> bool {
+        let now = Instant::now();
+        let limit = self.limit;
+
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            // a bucket untouched for a second is full again, just like a missing one
+            self.buckets.retain(|_, &mut (_, updated)| now.duration_since(updated).as_secs() < 1);
+        }
+
+        let bucket = self.buckets.entry(addr).or_insert((limit, now));
+        // worked out wide, as a bucket may be untouched for long enough to overflow a u64
+        let refill = now.duration_since(bucket.1).as_micros() * limit as u128 / 1_000_000;
+        if refill > 0 {
+            bucket.0 = (limit as u128).min(bucket.0 as u128 + refill) as u64;
+            bucket.1 = now;
+        }
+
+        if bucket.0 < len as u64 {
+            return false;
+        }
+        bucket.0 -= len as u64;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    const HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut limiter = RateLimiter::new(10_000);
+        assert!(limiter.allow(HOST, 10_000));
+        assert!(!limiter.allow(HOST, 1000));
+
+        // a tenth of a second or more is worth at least 1000 bytes
+        thread::sleep(Duration::from_millis(150));
+        assert!(limiter.allow(HOST, 1000));
+    }
+
+    #[test]
+    fn buckets_hold_no_more_than_a_seconds_worth() {
+        let mut limiter = RateLimiter::new(1000);
+        assert!(!limiter.allow(HOST, 1001));
+        assert!(limiter.allow(HOST, 1000));
+
+        thread::sleep(Duration::from_millis(1200));
+        assert!(!limiter.allow(HOST, 1001));
+        assert!(limiter.allow(HOST, 1000));
+        assert!(!limiter.allow(HOST, 100));
+    }
+
+    #[test]
+    fn every_host_has_a_bucket_of_its_own() {
+        let mut limiter = RateLimiter::new(1000);
+        assert!(limiter.allow(HOST, 1000));
+        assert!(!limiter.allow(HOST, 1));
+        assert!(limiter.allow(Ipv4Addr::new(10, 0, 0, 2), 1000));
+        assert!(!limiter.allow(HOST, 1));
+    }
+}
@@ -0,0 +1,281 @@
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, BigEndian};
+use commands::CHALLENGE_MAGIC;
+
+// how long connecting to the upstream server and its handshake may take
+const CONNECT_TIMEOUT_SECS: u64 = 5;
+
+// the relay is dropped if the upstream server sends no audio for this long
+const AUDIO_TIMEOUT_SECS: u64 = 10;
+
+/// What the upstream server sent.
+pub enum Received {
+    // this many bytes of audio were received into the buffer
+    Audio(usize),
+    // the upstream station announced a new song
+    Announce(String),
+    // nothing came in this time
+    Nothing,
+}
+
+/// A station of another rustcast server, received like any client would.
+pub struct Relay {
+    control: TcpStream,
+    audio: UdpSocket,
+
+    // bytes read from the control connection that do not make a whole reply yet
+    replies: Vec<u8>,
+
+    song_name: String,
+    last_audio: Instant,
+}
+
+impl Relay {
+    /// Connect to the server at `addr`, a `host:port`, and tune into `station_number`.
+    ///
+    /// Audio is received on a UDP port of its own, waiting up to `timeout` for each datagram.
+    /// Whatever arrives there is taken as coming from the upstream server, which may send from
+    /// another address than the one connected to, such as when it is multi-homed or behind NAT.
+    pub fn connect(addr: &str, station_number: u16, timeout: Duration) -> io::Result<Relay> {
+        let connect_timeout = Duration::from_secs(CONNECT_TIMEOUT_SECS);
+        let upstream = addr.to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "upstream address did not resolve"))?;
+        let audio = UdpSocket::bind("0.0.0.0:0")?;
+        audio.set_read_timeout(Some(timeout))?;
+        let mut control = TcpStream::connect_timeout(&upstream, connect_timeout)?;
+        control.set_read_timeout(Some(connect_timeout))?;
+
+        // a plain HELLO, so the upstream server neither PINGs nor sends anything new to us
+        let mut hellobuf = [0u8; 3];
+        BigEndian::write_u16(&mut hellobuf[1..], audio.local_addr()?.port());
+        control.write_all(&hellobuf)?;
+        match read_reply(&mut control)? {
+            Reply::Welcome => (),
+            Reply::Invalid(reason) => {
+                return Err(Error::new(ErrorKind::ConnectionRefused,
+                                      format!("HELLO rejected: {}", reason)))
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected WELCOME")),
+        }
+
+        let mut setstationbuf = [1u8, 0, 0];
+        BigEndian::write_u16(&mut setstationbuf[1..], station_number);
+        control.write_all(&setstationbuf)?;
+        let song_name = match read_reply(&mut control)? {
+            Reply::Announce(song_name) => song_name,
+            Reply::Invalid(reason) => {
+                return Err(Error::new(ErrorKind::ConnectionRefused,
+                                      format!("SET_STATION rejected: {}", reason)))
+            }
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected ANNOUNCE")),
+        };
+        control.set_nonblocking(true)?;
+
+        Ok(Relay {
+            control,
+            audio,
+            replies: Vec::new(),
+            song_name,
+            last_audio: Instant::now(),
+        })
+    }
+
+    /// The song the upstream station announced last.
+    pub fn song_name(&self) -> &str {
+        &self.song_name
+    }
+
+    /// Take in what the upstream server sent, waiting a while for a datagram of audio.
+    pub fn receive(&mut self, buffer: &mut [u8]) -> io::Result<Received> {
+        if let Some(song_name) = self.read_replies()? {
+            self.song_name = song_name.clone();
+            return Ok(Received::Announce(song_name));
+        }
+
+        let mut datagram = [0u8; 2048];
+        let len = match self.audio.recv(&mut datagram) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if self.last_audio.elapsed() > Duration::from_secs(AUDIO_TIMEOUT_SECS) {
+                    return Err(Error::new(ErrorKind::TimedOut, "upstream stopped sending audio"));
+                }
+                return Ok(Received::Nothing);
+            }
+            Err(e) => return Err(e),
+        };
+        self.last_audio = Instant::now();
+
+        if len == CHALLENGE_MAGIC.len() + 8 && datagram.starts_with(CHALLENGE_MAGIC) {
+            let mut verifybuf = [0u8; 9];
+            verifybuf[0] = 11;
+            verifybuf[1..].copy_from_slice(&datagram[CHALLENGE_MAGIC.len()..len]);
+            self.control.write_all(&verifybuf)?;
+            return Ok(Received::Nothing);
+        }
+        let len = len.min(buffer.len());
+        buffer[..len].copy_from_slice(&datagram[..len]);
+        Ok(Received::Audio(len))
+    }
+
+    /// Read what arrived on the control connection, returning the last song it announced.
+    fn read_replies(&mut self) -> io::Result<Option<String>> {
+        let mut buf = [0u8; 512];
+        loop {
+            match self.control.read(&mut buf) {
+                Ok(0) => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof,
+                                          "upstream closed the connection"))
+                }
+                Ok(n) => self.replies.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut announced = None;
+        loop {
+            let mut pending = &self.replies[..];
+            let reply = match read_reply(&mut pending) {
+                Ok(reply) => reply,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let consumed = self.replies.len() - pending.len();
+            self.replies.drain(..consumed);
+            match reply {
+                Reply::Announce(song_name) => announced = Some(song_name),
+                Reply::Invalid(reason) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted,
+                                          format!("upstream sent INVALID_COMMAND: {}", reason)))
+                }
+                Reply::Welcome | Reply::Other => (),
+            }
+        }
+        Ok(announced)
+    }
+}
+
+/// The replies a relay tells apart.
+enum Reply {
+    Welcome,
+    Announce(String),
+    Invalid(String),
+    // anything else of a known size, which is skipped
+    Other,
+}
+
+/// Read a reply to a plain HELLO client, failing with `UnexpectedEof` if it is not complete yet.
+fn read_reply<R: Read>(r: &mut R) -> io::Result<Reply> {
+    let mut reply_type = [0u8; 1];
+    r.read_exact(&mut reply_type)?;
+    match reply_type[0] {
+        0 | 6 | 7 => {
+            let mut rest = [0u8; 2];
+            r.read_exact(&mut rest)?;
+            match reply_type[0] {
+                0 => Ok(Reply::Welcome),
+                7 => Err(Error::new(ErrorKind::ConnectionAborted, "upstream said goodbye")),
+                _ => Ok(Reply::Other),
+            }
+        }
+        1 => Ok(Reply::Announce(read_string(r)?)),
+        2 => Ok(Reply::Invalid(read_string(r)?)),
+        reply_type => {
+            Err(Error::new(ErrorKind::InvalidData,
+                           format!("upstream sent an unknown reply: {}", reply_type)))
+        }
+    }
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let mut len = [0u8; 1];
+    r.read_exact(&mut len)?;
+    let mut s = vec![0u8; len[0] as usize];
+    r.read_exact(&mut s)?;
+    Ok(String::from_utf8_lossy(&s).into_owned())
+}
+
+/// Parse `<host>:<port>/<station>` into the address and the station.
+pub fn parse_upstream(s: &str) -> Option<(String, u16)> {
+    let (addr, station) = s.rsplit_once('/')?;
+    if !addr.contains(':') {
+        return None;
+    }
+    Some((addr.to_string(), station.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The replies in `buf` and what is left of it after them.
+    fn read_replies(mut buf: &[u8]) -> (Vec<Reply>, io::Result<()>, usize) {
+        let mut replies = Vec::new();
+        loop {
+            match read_reply(&mut buf) {
+                Ok(reply) => replies.push(reply),
+                Err(e) => return (replies, Err(e), buf.len()),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_upstream_takes_host_port_and_station() {
+        assert_eq!(parse_upstream("radio.example:8001/2"),
+                   Some(("radio.example:8001".to_string(), 2)));
+        assert_eq!(parse_upstream("127.0.0.1:8001/0"), Some(("127.0.0.1:8001".to_string(), 0)));
+        assert_eq!(parse_upstream("radio.example/2"), None);
+        assert_eq!(parse_upstream("radio.example:8001"), None);
+        assert_eq!(parse_upstream("radio.example:8001/"), None);
+        assert_eq!(parse_upstream("radio.example:8001/x"), None);
+        assert_eq!(parse_upstream("radio.example:8001/65536"), None);
+    }
+
+    #[test]
+    fn read_reply_reads_each_reply_whole() {
+        let mut buf = vec![0, 0, 3]; // WELCOME
+        buf.extend_from_slice(&[1, 4]);
+        buf.extend_from_slice(b"song"); // ANNOUNCE
+        buf.extend_from_slice(&[6, 0, 9]); // PING
+        buf.extend_from_slice(&[2, 3]);
+        buf.extend_from_slice(b"bad"); // INVALID_COMMAND
+        let (replies, end, left) = read_replies(&buf);
+        assert_eq!(end.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(left, 0);
+        assert_eq!(replies.len(), 4);
+        assert!(matches!(replies[0], Reply::Welcome));
+        assert!(matches!(replies[1], Reply::Announce(ref song) if song == "song"));
+        assert!(matches!(replies[2], Reply::Other));
+        assert!(matches!(replies[3], Reply::Invalid(ref reason) if reason == "bad"));
+    }
+
+    #[test]
+    fn read_reply_waits_for_the_rest_of_a_partial_announce() {
+        let mut announce = vec![1, 9];
+        announce.extend_from_slice(b"next song");
+        for cut in 1..announce.len() {
+            let (replies, end, _) = read_replies(&announce[..cut]);
+            assert!(replies.is_empty());
+            assert_eq!(end.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        }
+
+        // a relay keeps what it read until the rest comes in, as read_replies does
+        let mut pending = announce[..5].to_vec();
+        assert!(read_reply(&mut &pending[..]).is_err());
+        pending.extend_from_slice(&announce[5..]);
+        assert!(matches!(read_reply(&mut &pending[..]),
+                         Ok(Reply::Announce(ref song)) if song == "next song"));
+    }
+
+    #[test]
+    fn read_reply_fails_on_goodbye_and_unknown_replies() {
+        let (_, end, _) = read_replies(&[7, 0, 0]);
+        assert_eq!(end.unwrap_err().kind(), ErrorKind::ConnectionAborted);
+        let (_, end, _) = read_replies(&[42, 0, 0]);
+        assert_eq!(end.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,318 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Error, ErrorKind};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc;
+
+// days as schedules name them, from Monday
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A point in local time, as far as schedules care.
+#[derive(Clone, Copy)]
+pub struct LocalTime {
+    // days since Monday
+    pub weekday: usize,
+
+    // minutes since midnight
+    pub minute: u16,
+
+    // hours since the epoch, to tell one top of the hour from the next
+    pub hour: u64,
+}
+
+/// Where a schedule gets the time from, so that it can be made to run at any.
+pub trait Clock: Send {
+    fn now(&self) -> LocalTime;
+}
+
+/// The time of the system, in its time zone.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> LocalTime {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let t = secs as libc::time_t;
+        let mut tm: libc::tm = unsafe { mem::zeroed() };
+        unsafe {
+            libc::localtime_r(&t, &mut tm);
+        }
+        LocalTime {
+            weekday: (tm.tm_wday as usize + 6) % 7,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u16,
+            hour: (secs as i64 + tm.tm_gmtoff as i64) as u64 / 3600,
+        }
+    }
+}
+
+/// A time slot of a schedule and what plays in it.
+struct Slot {
+    // whether the slot starts on each day, from Monday
+    days: [bool; 7],
+
+    // minutes since midnight the slot starts and ends at; it runs past midnight if it ends first
+    start: u16,
+    end: u16,
+
+    playlist: Vec<PathBuf>,
+}
+
+impl Slot {
+    fn contains(&self, time: &LocalTime) -> bool {
+        if self.start < self.end {
+            self.days[time.weekday] && self.start <= time.minute && time.minute < self.end
+        } else {
+            (self.days[time.weekday] && time.minute >= self.start) ||
+            (self.days[(time.weekday + 6) % 7] && time.minute < self.end)
+        }
+    }
+}
+
+/// What a station plays when, by local time.
+pub struct Schedule {
+    slots: Vec<Slot>,
+
+    // played outside of every slot
+    default: Vec<PathBuf>,
+
+    // played at the top of every hour, if any
+    pub jingle: Option<PathBuf>,
+}
+
+impl Schedule {
+    /// The slot playing at `time`, the first one listed if several are, or `None` for the default
+    /// playlist.
+    pub fn program_at(&self, time: &LocalTime) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.contains(time))
+    }
+
+    /// The files `program_at` some time says to play.
+    pub fn playlist(&self, program: Option<usize>) -> &[PathBuf] {
+        match program {
+            Some(slot) => &self.slots[slot].playlist,
+            None => &self.default,
+        }
+    }
+}
+
+/// Load the schedule in `path`, one entry per line, as one of
+///
+/// * `<days> <HH:MM>-<HH:MM> <file> [<file>...]`, playing the files in turn during those hours
+///   of those days, where the days are `*` or a comma separated list of days such as `mon-fri` or
+///   `sat,sun`;
+/// * `default <file> [<file>...]`, playing the files at any other time, which is required;
+/// * `jingle <file>`, playing the file at the top of every hour.
+///
+/// The first slot listed wins where slots overlap. Files are relative to the schedule. Blank lines
+/// and lines starting with `#` are skipped.
+pub fn load(path: &str) -> io::Result<Schedule> {
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut slots = Vec::new();
+    let mut default = None;
+    let mut jingle = None;
+    for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || {
+            Error::new(ErrorKind::InvalidData,
+                       format!("{}:{}: expected <days> <HH:MM>-<HH:MM> <file>..., default \
+                                <file>... or jingle <file>",
+                               path,
+                               number + 1))
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let files = |fields: &[&str]| -> Vec<PathBuf> {
+            fields.iter().map(|file| dir.join(file)).collect()
+        };
+        match fields[0] {
+            "default" if fields.len() > 1 => default = Some(files(&fields[1..])),
+            "jingle" if fields.len() == 2 => jingle = Some(dir.join(fields[1])),
+            days if fields.len() > 2 => {
+                let (start, end) = fields[1].split_once('-').ok_or_else(invalid)?;
+                let (start, end) = (parse_minute(start).ok_or_else(invalid)?,
+                                    parse_minute(end).ok_or_else(invalid)?);
+                // such a slot could as well be meant to be empty as to last all day
+                if start == end {
+                    return Err(Error::new(ErrorKind::InvalidData,
+                                          format!("{}:{}: slot starts and ends at the same time",
+                                                  path,
+                                                  number + 1)));
+                }
+                slots.push(Slot {
+                    days: parse_days(days).ok_or_else(invalid)?,
+                    start,
+                    end,
+                    playlist: files(&fields[2..]),
+                });
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    let default = default.ok_or_else(|| {
+        Error::new(ErrorKind::InvalidData, format!("{}: expected a default playlist", path))
+    })?;
+    Ok(Schedule {
+        slots,
+        default,
+        jingle,
+    })
+}
+
+/// Parse `*` or a comma separated list of days and ranges of days such as `mon-fri,sun`.
+fn parse_days(s: &str) -> Option<[bool; 7]> {
+    if s == "*" {
+        return Some([true; 7]);
+    }
+    let day = |name: &str| DAYS.iter().position(|&day| day.eq_ignore_ascii_case(name));
+    let mut days = [false; 7];
+    for range in s.split(',') {
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (day(first)?, day(last)?),
+            None => (day(range)?, day(range)?),
+        };
+        // a range such as sat-mon runs through the end of the week
+        let mut d = first;
+        loop {
+            days[d] = true;
+            if d == last {
+                break;
+            }
+            d = (d + 1) % 7;
+        }
+    }
+    Some(days)
+}
+
+/// Parse `HH:MM` into minutes since midnight, up to `24:00`.
+fn parse_minute(s: &str) -> Option<u16> {
+    let (hours, minutes) = s.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?);
+    if minutes >= 60 || hours > 24 || hours * 60 + minutes > 24 * 60 {
+        return None;
+    }
+    Some((hours * 60 + minutes) as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::process;
+
+    /// Monday through Sunday as 0 to 6, at `HH:MM`.
+    fn at(weekday: usize, time: &str) -> LocalTime {
+        LocalTime {
+            weekday,
+            minute: parse_minute(time).unwrap(),
+            hour: 0,
+        }
+    }
+
+    /// The directory the schedule `name` is written to.
+    fn schedule_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rustcast-schedule-{}-{}", process::id(), name))
+    }
+
+    /// Write `contents` to a schedule of its own and load it.
+    fn load_schedule(name: &str, contents: &str) -> io::Result<Schedule> {
+        let dir = schedule_dir(name);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("schedule");
+        fs::write(&path, contents).unwrap();
+        let schedule = load(path.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        schedule
+    }
+
+    #[test]
+    fn parse_days_takes_lists_and_ranges() {
+        assert_eq!(parse_days("*"), Some([true; 7]));
+        assert_eq!(parse_days("mon"), Some([true, false, false, false, false, false, false]));
+        assert_eq!(parse_days("Mon-Fri"), Some([true, true, true, true, true, false, false]));
+        assert_eq!(parse_days("sat,sun"), Some([false, false, false, false, false, true, true]));
+        assert_eq!(parse_days("tue,thu-fri"),
+                   Some([false, true, false, true, true, false, false]));
+        // through the end of the week
+        assert_eq!(parse_days("sat-mon"), Some([true, false, false, false, false, true, true]));
+        assert_eq!(parse_days("sun-sun"), Some([false, false, false, false, false, false, true]));
+
+        for days in &["", "monday", "mon-", "-fri", "mon,,fri", "mon-fri-sun", "**"] {
+            assert_eq!(parse_days(days), None, "{}", days);
+        }
+    }
+
+    #[test]
+    fn parse_minute_takes_times_up_to_midnight() {
+        assert_eq!(parse_minute("00:00"), Some(0));
+        assert_eq!(parse_minute("7:05"), Some(7 * 60 + 5));
+        assert_eq!(parse_minute("23:59"), Some(23 * 60 + 59));
+        assert_eq!(parse_minute("24:00"), Some(24 * 60));
+
+        for time in &["24:01", "25:00", "12:60", "12", "12:", ":30", "-1:00", "12:-5", "1:2:3",
+                      "99999999999:00"] {
+            assert_eq!(parse_minute(time), None, "{}", time);
+        }
+    }
+
+    #[test]
+    fn slots_run_past_midnight_into_the_next_day() {
+        let schedule = load_schedule("overnight",
+                                     "fri 22:00-02:00 late.mp3\n\
+                                      default day.mp3\n")
+            .unwrap();
+        // Friday night and the small hours of Saturday
+        assert_eq!(schedule.program_at(&at(4, "22:00")), Some(0));
+        assert_eq!(schedule.program_at(&at(4, "23:59")), Some(0));
+        assert_eq!(schedule.program_at(&at(5, "00:00")), Some(0));
+        assert_eq!(schedule.program_at(&at(5, "01:59")), Some(0));
+        assert_eq!(schedule.program_at(&at(5, "02:00")), None);
+        assert_eq!(schedule.program_at(&at(4, "21:59")), None);
+        // but not Friday's small hours, nor Saturday night
+        assert_eq!(schedule.program_at(&at(4, "01:00")), None);
+        assert_eq!(schedule.program_at(&at(5, "23:00")), None);
+    }
+
+    #[test]
+    fn the_default_plays_outside_of_the_slots() {
+        let schedule = load_schedule("default",
+                                     "# weekdays\n\
+                                      \n\
+                                      mon-fri 06:00-09:00 morning.mp3 news.mp3\n\
+                                      * 08:00-10:00 late.mp3\n\
+                                      jingle top.mp3\n\
+                                      default a.mp3 b.mp3\n")
+            .unwrap();
+        let dir = schedule_dir("default");
+        assert_eq!(schedule.jingle, Some(dir.join("top.mp3")));
+
+        assert_eq!(schedule.program_at(&at(0, "05:59")), None);
+        assert_eq!(schedule.playlist(None), &[dir.join("a.mp3"), dir.join("b.mp3")][..]);
+        // the first slot listed wins where they overlap
+        assert_eq!(schedule.program_at(&at(2, "08:30")), Some(0));
+        assert_eq!(schedule.playlist(Some(0)),
+                   &[dir.join("morning.mp3"), dir.join("news.mp3")][..]);
+        assert_eq!(schedule.program_at(&at(6, "08:30")), Some(1));
+        assert_eq!(schedule.program_at(&at(2, "09:30")), Some(1));
+        assert_eq!(schedule.program_at(&at(2, "10:00")), None);
+    }
+
+    #[test]
+    fn load_rejects_bad_schedules() {
+        for (name, contents) in &[("no-default", "* 06:00-09:00 a.mp3\n"),
+                                  ("empty-slot", "* 06:00-06:00 a.mp3\ndefault b.mp3\n"),
+                                  ("no-range", "* 06:00 a.mp3\ndefault b.mp3\n"),
+                                  ("bad-days", "mon-xyz 06:00-09:00 a.mp3\ndefault b.mp3\n"),
+                                  ("bad-time", "* 06:00-25:00 a.mp3\ndefault b.mp3\n"),
+                                  ("no-files", "default\n"),
+                                  ("two-jingles", "jingle a.mp3 b.mp3\ndefault b.mp3\n")] {
+            let e = load_schedule(name, contents).err().expect(name);
+            assert_eq!(e.kind(), ErrorKind::InvalidData, "{}", name);
+        }
+    }
+}
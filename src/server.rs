@@ -1,37 +1,110 @@
-use std::collections::HashSet;
+use std::cmp;
 use std::io::{self, ErrorKind};
+use std::path::Path;
 use std::rc::Rc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 use std::thread;
-use std::fs::File;
-use std::io::Read;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::io::{Seek, SeekFrom};
+use std::net::{SocketAddr, IpAddr};
 
 use byteorder::{ByteOrder, BigEndian};
 use commands::*;
 use slab;
 use mio::*;
-use mio::tcp::*;
-use mio::udp::*;
+use mio::net::{TcpListener, UdpSocket};
+use mio::unix::UnixReady;
 
-use connection::Connection;
+use acl::{self, AccessList};
+use auth::{self, AuthKey};
+use connection::{Connection, UdpAddress};
+use eventlog::EventLog;
+use ingest;
+use json;
+use metrics::{Metrics, MetricsListener, StationMetrics, StationSample};
+use ratelimit::RateLimiter;
+use station::{broadcast_channel, Action, Health, NowPlaying, SourceSpec, StationConfig,
+              StationContext, StationEvent};
 
 type Slab<T> = slab::Slab<T, Token>;
 
-type UdpAddress = (Ipv4Addr, u16);
+// clients that negotiated keepalives are dropped after missing this many PINGs in a row
+const MISSED_PINGS: u32 = 3;
 
-enum Action {
-    Add(UdpAddress),
-    Remove(UdpAddress),
+// unanswered UDP challenges are sent again this often, in case the datagram was lost
+const CHALLENGE_INTERVAL_MS: u64 = 1000;
+
+// times a UDP challenge is sent before the client is disconnected for not answering it
+const MAX_CHALLENGE_SENDS: u32 = 5;
+
+/// A station streamed to its listeners by a dedicated thread.
+struct Station {
+    // name shown to clients listing the stations
+    name: String,
+
+    // shared with the station thread
+    now_playing: Arc<Mutex<NowPlaying>>,
+
+    // used to add and remove recipients
+    channel: Sender<Action>,
+
+    // updated by the station thread
+    metrics: Arc<StationMetrics>,
+
+    // whether encoders may push audio to the station
+    live: bool,
+}
+
+/// Tunables of the server, set from the command line.
+pub struct Settings {
+    // how often clients that negotiated keepalives are sent a PING
+    pub ping_interval: Duration,
+
+    // most clients a station streams to at once, if limited
+    pub max_listeners: Option<usize>,
+
+    // most control connections a single IP may have open at once, if limited
+    pub max_connections_per_ip: Option<usize>,
+
+    // keys clients can AUTH with; the server does not offer CAP_AUTH without any
+    pub auth_keys: Vec<AuthKey>,
+
+    // stations only clients that authenticated with a key allowing them may listen to
+    pub restricted_stations: Vec<u16>,
+
+    // whether clients must echo a cookie sent to a UDP sink before it is sent any audio
+    pub udp_challenge: bool,
+
+    // bytes per second the stations may send to a single host altogether, if limited
+    pub max_destination_rate: Option<u64>,
+
+    // who may connect and listen to which stations
+    pub access_list: AccessList,
+
+    // file the access list is reloaded from on SIGHUP, if any
+    pub access_list_path: Option<String>,
+
+    // kbit/s of the silence stations play while they have nothing else to
+    pub silence_bitrate: u32,
+
+    // how much of the audio just sent stations keep to send new listeners at once, if any
+    pub time_shift: Option<Duration>,
 }
 
 pub struct Server {
     // main socket for our server
     sock: TcpListener,
 
+    // port of the main socket, which clients may not have audio sent to
+    port: u16,
+
+    // sends UDP challenges
+    challenge_sock: UdpSocket,
+
+    // caps what the stations and challenges send to any one host, if limited
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+
     // token of our server. we keep track of it here instead of doing `const SERVER = Token(0)`.
     token: Token,
 
@@ -42,72 +115,94 @@ pub struct Server {
     events: Events,
 
     // available stations on this server
-    stations: Vec<String>,
+    stations: Vec<Station>,
 
-    channels: Vec<Sender<Action>>,
-}
+    settings: Settings,
 
-fn broadcast_channel(rx: Receiver<Action>, filename: String) {
-    let mut recipients = HashSet::<UdpAddress>::new();
-    let mut f = File::open(filename).unwrap(); // XXX or panic!
-    let addr = ("0.0.0.0:".to_string() + "0")
-        .parse::<SocketAddr>()
-        .unwrap();
-
-    let sock = UdpSocket::bind(&addr).unwrap();
-    loop {
-        match rx.try_recv() {
-            Ok(action) => {
-                match action {
-                    Action::Add(udpaddress) => {
-                        debug!("adding: {:?}", udpaddress);
-                        recipients.insert(udpaddress);
-                    }
-                    Action::Remove(udpaddress) => {
-                        debug!("removing: {:?}", udpaddress);
-                        recipients.remove(&udpaddress);
-                    }
-                }
-            }
-            Err(_) => (),
-            // Err(mpsc::TryRecvError::Empty) => 65535,
-            // Err(mpsc::TryRecvError::Disconnected) => return,
-        };
+    // counters exposed on the metrics endpoint
+    metrics: Metrics,
 
-        let mut buffer = [0; 1024];
-        let len = match f.read(&mut buffer[..]) {
-            Ok(0) => {
-                f.seek(SeekFrom::Start(0)).unwrap();
-                0
-            }
-            Ok(n) => n,
-            _ => {
-                println!("Error reading file!");
-                return;
-            }
-        };
+    // serves the metrics over HTTP, if enabled
+    metrics_listener: Option<MetricsListener>,
 
-        for recipient in &recipients {
-            debug!("rec: {:?}", recipient);
-            let dest = SocketAddr::new(IpAddr::V4(recipient.0), recipient.1);
-            sock.send_to(&buffer[0..len], &dest).unwrap();
-        }
-        thread::sleep(Duration::new(0, 62_500_000)); // 62.5ms
-    }
+    // song changes and errors reported by the station threads
+    station_events: Receiver<StationEvent>,
+
+    // structured log of what happens on the server, if enabled
+    event_log: Option<EventLog>,
+}
+
+/// Build a STATION_STATUS reply.
+fn station_status(station_number: u16, health: Health) -> Vec<u8> {
+    let mut statusbuf: Vec<u8> = vec![0; 4];
+    statusbuf[0] = 8; // reply_type
+    BigEndian::write_u16(&mut statusbuf[1..3], station_number);
+    statusbuf[3] = health.status_byte();
+    statusbuf
+}
+
+/// Append `s` to `buf` prefixed by its one byte length, truncating it to 255 bytes if needed.
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    let bytes = &s.as_bytes()[..cmp::min(s.len(), 255)];
+    buf.push(bytes.len() as u8);
+    buf.extend_from_slice(bytes);
 }
 
 impl Server {
-    pub fn new(sock: TcpListener, stations: Vec<String>) -> Server {
-        let mut channels = Vec::<Sender<Action>>::new();
-        for i in 0..stations.len() {
-            let station = stations[i].clone();
-            let (tx, rx): (Sender<Action>, Receiver<Action>) = mpsc::channel();
-            thread::spawn(move || broadcast_channel(rx, station));
-            channels.push(tx);
+    pub fn new(sock: TcpListener, configs: Vec<StationConfig>, settings: Settings) -> Server {
+        let limiter = settings.max_destination_rate
+            .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit))));
+        let (events_tx, station_events) = mpsc::channel();
+        let channels: Vec<(Sender<Action>, Receiver<Action>)> =
+            configs.iter().map(|_| mpsc::channel()).collect();
+        let senders: Vec<Sender<Action>> = channels.iter().map(|(tx, _)| tx.clone()).collect();
+        let mut stations = Vec::<Station>::new();
+        for (number, (config, (tx, rx))) in configs.into_iter().zip(channels).enumerate() {
+            let name = match config.primary {
+                SourceSpec::File(ref file) => {
+                    Path::new(file)
+                        .file_stem()
+                        .map_or_else(|| file.clone(), |stem| stem.to_string_lossy().into_owned())
+                }
+                ref primary => primary.to_string(),
+            };
+            let now_playing = Arc::new(Mutex::new(NowPlaying::new(config.primary.to_string())));
+            let live = matches!(config.primary, SourceSpec::Live);
+            let metrics = Arc::new(StationMetrics::default());
+            let context = StationContext {
+                number: number as u16,
+                config,
+                now_playing: now_playing.clone(),
+                limiter: limiter.clone(),
+                metrics: metrics.clone(),
+                events: events_tx.clone(),
+                silence_bitrate: settings.silence_bitrate,
+                time_shift: settings.time_shift,
+                stations: senders.clone(),
+            };
+            thread::spawn(move || broadcast_channel(rx, context));
+            stations.push(Station {
+                name,
+                now_playing,
+                channel: tx,
+                metrics,
+                live,
+            });
         }
 
+        let port = sock.local_addr().expect("Failed to get server address").port();
+        let challenge_addr = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
+        let challenge_sock = UdpSocket::bind(&challenge_addr)
+            .expect("Failed to bind challenge socket");
+
         Server {
-            sock: sock,
+            sock,
+
+            port,
+
+            challenge_sock,
+
+            limiter,
 
             // Give our server token a number much larger than our slab capacity. The slab used to
             // track an internal offset, but does not anymore.
@@ -121,37 +216,150 @@ impl Server {
             events: Events::with_capacity(1024),
 
             // vector of available stations on this server
-            stations: stations,
+            stations,
+
+            settings,
+
+            metrics: Metrics::default(),
+
+            metrics_listener: None,
+
+            station_events,
+
+            event_log: None,
+        }
+    }
+
+    /// Write the structured event log to `event_log`.
+    pub fn log_events(&mut self, event_log: EventLog) {
+        self.event_log = Some(event_log);
+    }
+
+    /// Add an event about the connection `token` to the event log, if there is one.
+    ///
+    /// The event carries the peer address of the connection and the station it listens to.
+    fn log_event(&mut self, event: &str, token: Token, fields: &[(&str, String)]) {
+        let (peer, station) = match self.conns.get(token) {
+            Some(c) => (Some(c.get_addr()), c.get_current_channel()),
+            None => (None, None),
+        };
+        if let Some(ref mut event_log) = self.event_log {
+            event_log.write(event, Some(token.0), peer, station, fields);
+        }
+    }
 
-            channels: channels,
+    /// Report what the station threads sent since the last tick.
+    ///
+    /// Listeners are announced the song a station plays when it switches sources, and the ones
+    /// that negotiated CAP_STATION_STATUS are told about its health.
+    fn drain_station_events(&mut self) {
+        while let Ok(event) = self.station_events.try_recv() {
+            let (event, station, fields) = match event {
+                StationEvent::SongChange(station, song_name, announce) => {
+                    debug!("station {} now playing {}", station, song_name);
+                    if announce {
+                        self.announce(station, &song_name);
+                    }
+                    ("song_change", station, vec![("song_name", json::quote(&song_name))])
+                }
+                StationEvent::Error(station, message) => {
+                    console!("station {}: {}", station, message);
+                    ("station_error", station, vec![("message", json::quote(&message))])
+                }
+                StationEvent::HealthChange(station, health, song_name) => {
+                    console!("station {} is {}, playing {}", station, health.name(), song_name);
+                    if health != Health::Offline {
+                        self.announce(station, &song_name);
+                    }
+                    self.notify_station_status(station, health);
+                    ("station_health",
+                     station,
+                     vec![("state", json::quote(health.name())),
+                          ("song_name", json::quote(&song_name))])
+                }
+                StationEvent::SinkFailed(station, sink, message) => {
+                    console!("station {}: dropping sink {}:{}: {}",
+                             station,
+                             sink.0,
+                             sink.1,
+                             message);
+                    ("sink_error",
+                     station,
+                     vec![("sink", json::quote(&format!("{}:{}", sink.0, sink.1))),
+                          ("message", json::quote(&message))])
+                }
+            };
+            if let Some(ref mut event_log) = self.event_log {
+                event_log.write(event, None, None, Some(station), &fields);
+            }
         }
     }
 
+    /// Send an ANNOUNCE of `song_name` to the listeners of `station_number`.
+    fn announce(&mut self, station_number: u16, song_name: &str) {
+        let mut announcebuf = vec![1]; // reply_type
+        push_string(&mut announcebuf, song_name);
+        self.send_to_listeners(station_number, Rc::new(announcebuf), |_| true);
+    }
+
+    /// Send a STATION_STATUS to the listeners of `station_number` that negotiated
+    /// CAP_STATION_STATUS.
+    fn notify_station_status(&mut self, station_number: u16, health: Health) {
+        let message = Rc::new(station_status(station_number, health));
+        self.send_to_listeners(station_number,
+                               message,
+                               |c| c.has_capability(CAP_STATION_STATUS));
+    }
+
+    /// Queue `message` for the listeners of `station_number` that `wanted` picks.
+    fn send_to_listeners<F: Fn(&Connection) -> bool>(&mut self,
+                                                     station_number: u16,
+                                                     message: Rc<Vec<u8>>,
+                                                     wanted: F) {
+        for c in self.conns.iter_mut() {
+            if c.is_reset() || c.get_current_channel() != Some(station_number) || !wanted(c) {
+                continue;
+            }
+            if let Err(e) = c.send_message(message.clone()) {
+                warn!("Failed to queue message for {:?}: {:?}", c.token, e);
+                c.mark_reset();
+                continue;
+            }
+            // the message needs a write interest, which `tick` registers for idle connections
+            c.mark_idle();
+        }
+    }
+
+    /// Serve the metrics over HTTP on `sock`, at `/metrics`.
+    pub fn serve_metrics(&mut self, sock: TcpListener) {
+        self.metrics_listener = Some(MetricsListener::new(sock, Token(10_000_001)));
+    }
+
+    /// Accept encoders pushing live audio to stations on `listener`.
+    pub fn serve_ingest(&self, listener: std::net::TcpListener) {
+        let stations = self.stations
+            .iter()
+            .map(|station| if station.live { Some(station.channel.clone()) } else { None })
+            .collect();
+        ingest::serve(listener, self.settings.auth_keys.clone(), stations);
+    }
+
     pub fn run(&mut self, poll: &mut Poll) -> io::Result<()> {
 
-        try!(self.register(poll));
+        self.register(poll)?;
 
         info!("Server run loop starting...");
         loop {
-            let cnt = try!(poll.poll(&mut self.events, Some(Duration::from_millis(100))));
-
-            let mut i = 0;
-
-            // trace!("processing events... cnt={}; len={}",
-            //        cnt,
-            //        self.events.len());
+            poll.poll(&mut self.events, Some(Duration::from_millis(100)))?;
 
             // Iterate over the notifications. Each event provides the token
             // it was registered with (which usually represents, at least, the
             // handle that the event is about) as well as information about
             // what kind of event occurred (readable, writable, signal, etc.)
-            while i < cnt {
-                let event = self.events.get(i).expect("Failed to get event");
-
-                trace!("event={:?}; idx={:?}", event, i);
-                self.ready(poll, event.token(), event.kind());
-
-                i += 1;
+            let events: Vec<Event> = self.events.iter().collect();
+            for event in events {
+                trace!("event={:?}", event);
+                self.ready(poll, event.token(), event.readiness());
             }
 
             self.tick(poll);
@@ -163,15 +371,34 @@ impl Server {
     /// This keeps the registration details neatly tucked away inside of our implementation.
     pub fn register(&mut self, poll: &mut Poll) -> io::Result<()> {
         poll.register(&self.sock, self.token, Ready::readable(), PollOpt::edge())
-            .or_else(|e| {
+            .map_err(|e| {
                 error!("Failed to register server {:?}, {:?}", self.token, e);
-                Err(e)
-            })
+                e
+            })?;
+
+        if let Some(ref metrics_listener) = self.metrics_listener {
+            metrics_listener.register(poll)
+                .map_err(|e| {
+                    error!("Failed to register metrics listener, {:?}", e);
+                    e
+                })?;
+        }
+        Ok(())
     }
 
     fn tick(&mut self, poll: &mut Poll) {
         // trace!("Handling end of tick");
 
+        if acl::take_reload_request() {
+            self.reload_access_list();
+        }
+        self.drain_station_events();
+        self.keepalive();
+        self.resend_challenges();
+        if let Some(ref mut metrics_listener) = self.metrics_listener {
+            metrics_listener.close_idle();
+        }
+
         let mut reset_tokens = Vec::new();
 
         for c in self.conns.iter_mut() {
@@ -188,14 +415,8 @@ impl Server {
         }
 
         for token in reset_tokens {
-            let current_channel = self.find_connection_by_token(token)
-                .get_current_channel() as usize;
-            let ip = self.find_connection_by_token(token).get_addr();
-            let udp_port = self.find_connection_by_token(token).get_udp_port();
-            if current_channel < self.channels.len() {
-                debug!("sending message to remove port: {}", udp_port);
-                self.channels[current_channel].send(Action::Remove((ip, udp_port))).unwrap();
-            }
+            self.log_event("disconnect", token, &[]);
+            self.leave_station(token);
 
             match self.conns.remove(token) {
                 Some(_c) => {
@@ -208,19 +429,129 @@ impl Server {
         }
     }
 
+    /// Read the access list again, keeping the current one if that fails.
+    ///
+    /// The new rules apply to connections accepted and stations set from now on, and connections
+    /// they no longer permit are closed.
+    fn reload_access_list(&mut self) {
+        let path = match self.settings.access_list_path {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        match AccessList::load(&path) {
+            Ok(access_list) => {
+                console!("reloaded access list from {}", path);
+                self.settings.access_list = access_list;
+                self.enforce_access_list();
+            }
+            Err(e) => error!("Failed to reload access list, keeping the old one: {}", e),
+        }
+    }
+
+    /// Close the connections the access list does not permit, to be connected at all or to
+    /// listen to their station.
+    fn enforce_access_list(&mut self) {
+        let mut denied = Vec::new();
+        for c in self.conns.iter() {
+            if c.is_reset() || c.is_to_be_removed() {
+                continue;
+            }
+            let addr = c.get_addr();
+            if !self.settings.access_list.permits(addr) {
+                denied.push((c.token, None));
+            } else if let Some(station_number) = c.get_current_channel() {
+                if !self.settings.access_list.permits_station(addr, station_number) {
+                    denied.push((c.token, Some(station_number)));
+                }
+            }
+        }
+
+        for (token, station_number) in denied {
+            let addr = self.conns[token].get_addr();
+            self.leave_station(token);
+            match station_number {
+                None => {
+                    console!("{:?}: {} is no longer allowed to connect, sending \
+                              INVALID_COMMAND; closing connection",
+                             token,
+                             addr);
+                    let reply = format!("connections from {} are not allowed", addr);
+                    self.disconnect_with_invalid_command(token, "access_denied", &reply);
+                }
+                Some(station_number) => {
+                    console!("{:?}: station {} is no longer available to it, sending \
+                              INVALID_COMMAND; closing connection",
+                             token,
+                             station_number);
+                    let reply = format!("station {} is not available from {}",
+                                        station_number,
+                                        addr);
+                    self.disconnect_with_invalid_command(token, "station_access_denied", &reply);
+                }
+            }
+            // the INVALID_COMMAND needs a write interest, which `tick` registers for idle
+            // connections
+            self.find_connection_by_token(token).mark_idle();
+        }
+    }
+
+    /// PING clients that negotiated keepalives and reset the ones that stopped answering.
+    ///
+    /// Resetting a connection takes it off its station in `tick`, so half-open sessions stop
+    /// receiving audio. Legacy clients are not PINGed; their sockets have TCP keepalives enabled
+    /// instead, which end half-open sessions with an error event.
+    fn keepalive(&mut self) {
+        let now = Instant::now();
+        let ping_interval = self.settings.ping_interval;
+
+        for c in self.conns.iter_mut() {
+            if c.is_reset() || !c.is_handshake_done() || !c.has_capability(CAP_KEEPALIVE) {
+                continue;
+            }
+
+            if now.duration_since(c.get_last_pong()) > ping_interval.saturating_mul(MISSED_PINGS) {
+                console!("{:?}: no PONG for {} PINGs; closing connection",
+                         c.token,
+                         MISSED_PINGS);
+                c.mark_reset();
+                self.metrics.keepalive_timeouts += 1;
+            } else if now.duration_since(c.get_last_ping()) >= ping_interval {
+                match c.send_ping() {
+                    Ok(sequence) => {
+                        trace!("{:?}: sent PING {}", c.token, sequence);
+                        // the PING needs a write interest, which `tick` registers for idle
+                        // connections
+                        c.mark_idle();
+                    }
+                    Err(e) => {
+                        warn!("Failed to queue PING for {:?}: {:?}", c.token, e);
+                        c.mark_reset();
+                    }
+                }
+            }
+        }
+    }
+
     fn ready(&mut self, poll: &mut Poll, token: Token, event: Ready) {
         debug!("{:?} event = {:?}", token, event);
 
-        if event.is_error() {
+        if self.metrics_listener.as_ref().is_some_and(|listener| listener.owns(token)) {
+            self.metrics_ready(poll, token, event);
+            return;
+        }
+
+        let unix_event = UnixReady::from(event);
+
+        if unix_event.is_error() {
             warn!("Error event for {:?}", token);
             self.find_connection_by_token(token).mark_reset();
             return;
         }
 
-        if event.is_hup() {
+        if unix_event.is_hup() {
             trace!("Hup event for {:?}", token);
             self.find_connection_by_token(token).mark_reset();
-            println!("{:?}: client closed connection", token);
+            console!("{:?}: client closed connection", token);
             return;
         }
 
@@ -270,6 +601,47 @@ impl Server {
         }
     }
 
+    /// Handle an event for the metrics listener or one of its clients.
+    fn metrics_ready(&mut self, poll: &mut Poll, token: Token, event: Ready) {
+        let mut listener = match self.metrics_listener.take() {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        if token == listener.token {
+            listener.accept(poll);
+        } else {
+            if event.is_readable() && listener.readable(token) {
+                listener.respond(token, || self.render_metrics());
+            }
+            if event.is_writable() {
+                listener.writable(token);
+            }
+        }
+
+        self.metrics_listener = Some(listener);
+    }
+
+    /// The current metrics in the Prometheus text format.
+    fn render_metrics(&self) -> String {
+        let connected_clients = self.conns.iter().filter(|c| !c.is_reset()).count();
+        let stations: Vec<StationSample> = self.stations
+            .iter()
+            .enumerate()
+            .map(|(number, station)| {
+                StationSample {
+                    name: &station.name,
+                    listeners: self.conns
+                        .iter()
+                        .filter(|c| !c.is_reset() && c.get_current_channel() == Some(number as u16))
+                        .count(),
+                    metrics: &station.metrics,
+                }
+            })
+            .collect();
+        self.metrics.render(connected_clients, &stations)
+    }
+
     /// Accept a _new_ client connection.
     ///
     /// The server will keep track of the new connection and forward any events from the poller
@@ -296,11 +668,24 @@ impl Server {
                     return;
                 }
             };
-            // let SocketAddr::V4(ip) = addr;
+
+            let connections = self.conns
+                .iter()
+                .filter(|c| !c.is_reset() && c.get_addr() == *ip.ip())
+                .count();
+            let over_limit = self.settings
+                .max_connections_per_ip
+                .is_some_and(|max_connections| connections >= max_connections);
+
+            // TCP keepalives catch half-open legacy clients, which cannot answer PINGs
+            if let Err(e) = sock.set_keepalive(Some(self.settings.ping_interval)) {
+                warn!("Failed to enable TCP keepalives for {}: {:?}", ip, e);
+            }
+
             let token = match self.conns.vacant_entry() {
                 Some(entry) => {
                     debug!("registering {:?} with poller", entry.index());
-                    let c = Connection::new(sock, entry.index(), ip.ip().clone());
+                    let c = Connection::new(sock, entry.index(), *ip.ip());
                     entry.insert(c).index()
                 }
                 None => {
@@ -308,10 +693,30 @@ impl Server {
                     return;
                 }
             };
+            self.metrics.connections_accepted += 1;
+            self.log_event("connect", token, &[]);
+
+            if !self.settings.access_list.permits(*ip.ip()) {
+                console!("{:?}: {} is not allowed to connect, sending INVALID_COMMAND; closing \
+                          connection",
+                         token,
+                         ip.ip());
+                let reply = format!("connections from {} are not allowed", ip.ip());
+                self.disconnect_with_invalid_command(token, "access_denied", &reply);
+            } else if over_limit {
+                console!("{:?}: {} already has {} connections, sending INVALID_COMMAND; closing \
+                          connection",
+                         token,
+                         ip.ip(),
+                         connections);
+                self.disconnect_with_invalid_command(token,
+                                                     "too_many_connections",
+                                                     "too many connections from your address");
+            }
 
             match self.find_connection_by_token(token).register(poll) {
                 Ok(_) => {
-                    println!("{:?}: new client connected; expecting HELLO", token);
+                    console!("{:?}: new client connected; expecting HELLO", token);
                 }
                 Err(e) => {
                     error!("Failed to register {:?} connection with poller, {:?}",
@@ -323,23 +728,317 @@ impl Server {
         }
     }
 
-    fn disconnect_with_invalid_command(&mut self, token: Token, reply: &str) {
-        let reply_string = reply.to_string();
-        let reply_string_size = reply_string.len();
-        let mut invalidbuf: Vec<u8> = vec![0; 2];
-        unsafe {
-            invalidbuf.set_len(2);
-        }
-        invalidbuf[0] = 2; // reply_type
-        invalidbuf[1] = reply_string_size as u8;
-        let mut reply_string_vec = reply_string.into_bytes();
-        invalidbuf.append(&mut reply_string_vec);
+    /// Send an INVALID_COMMAND with `reply` and close the connection, counting it under
+    /// `reason` in the metrics.
+    fn disconnect_with_invalid_command(&mut self,
+                                       token: Token,
+                                       reason: &'static str,
+                                       reply: &str) {
+        self.metrics.count_invalid_command(reason);
+        self.log_event("invalid_command",
+                       token,
+                       &[("reason", json::quote(reason)), ("message", json::quote(reply))]);
+        let mut invalidbuf = vec![2]; // reply_type
+        push_string(&mut invalidbuf, reply);
         self.find_connection_by_token(token)
-            .send_message(Rc::new(invalidbuf.to_vec()))
+            .send_message(Rc::new(invalidbuf))
             .ok();
         self.find_connection_by_token(token).mark_to_be_removed();
     }
 
+    /// Take a connection off the station it is listening to, if any.
+    fn leave_station(&mut self, token: Token) {
+        let current_channel = match self.find_connection_by_token(token).get_current_channel() {
+            Some(current_channel) => current_channel as usize,
+            None => return,
+        };
+        debug!("sending message to remove sinks of {:?}", token);
+        if self.stations[current_channel].channel.send(Action::Remove(token)).is_err() {
+            error!("station {} is gone", current_channel);
+        }
+        self.find_connection_by_token(token).set_current_channel(None);
+    }
+
+    /// Whether `token` may listen to `station_number`, which it must have authenticated for if
+    /// the station is restricted.
+    fn authorized(&self, token: Token, station_number: usize) -> bool {
+        let station_number = station_number as u16;
+        if !self.settings.restricted_stations.contains(&station_number) {
+            return true;
+        }
+        self.conns[token]
+            .get_auth_key()
+            .is_some_and(|key| self.settings.auth_keys[key].allows(station_number))
+    }
+
+    /// Handle an AUTH, checking `mac` against the nonce sent in WELCOME.
+    ///
+    /// Success is not acknowledged; a failure is answered with an INVALID_COMMAND like any other
+    /// bad command.
+    fn authenticate(&mut self, token: Token, key_name: &str, mac: &[u8; MAC_LEN]) {
+        console!("{:?}: received AUTH with key {}", token, key_name);
+
+        let nonce = match self.find_connection_by_token(token).get_nonce() {
+            Some(nonce) => nonce,
+            None => {
+                console!("{:?}: AUTH without a nonce, sending INVALID_COMMAND; closing connection",
+                         token);
+                self.disconnect_with_invalid_command(token,
+                                                     "auth_without_nonce",
+                                                     "server received an AUTH command without \
+                                                      agreeing on authentication in HELLO");
+                return;
+            }
+        };
+
+        let key = self.settings
+            .auth_keys
+            .iter()
+            .position(|key| key.name == key_name && key.verify(&nonce, mac));
+        match key {
+            Some(key) => {
+                console!("{:?}: authenticated with key {}", token, key_name);
+                self.find_connection_by_token(token).set_auth_key(key);
+            }
+            None => {
+                console!("{:?}: AUTH failed, sending INVALID_COMMAND; closing connection", token);
+                self.disconnect_with_invalid_command(token,
+                                                     "auth_failed",
+                                                     "authentication failed");
+            }
+        }
+    }
+
+    /// Whether `station_number` has no room for `token` to join it.
+    ///
+    /// A connection already listening to the station does not count against it, so re-setting
+    /// the same station always succeeds.
+    fn station_full(&self, token: Token, station_number: usize) -> bool {
+        let max_listeners = match self.settings.max_listeners {
+            Some(max_listeners) => max_listeners,
+            None => return false,
+        };
+        let listeners = self.conns
+            .iter()
+            .filter(|c| {
+                c.token != token && !c.is_reset() &&
+                c.get_current_channel() == Some(station_number as u16)
+            })
+            .count();
+        listeners >= max_listeners
+    }
+
+    /// Tell the station a connection listens to, if any, about its current set of sinks.
+    ///
+    /// Only sinks the client proved to receive on may be sent a burst: those that answered a
+    /// challenge and, without challenges, those at the client's own address, which `challenge`
+    /// leaves alone.
+    fn update_sinks(&mut self, token: Token) {
+        let current_channel = match self.find_connection_by_token(token).get_current_channel() {
+            Some(current_channel) => current_channel as usize,
+            None => return,
+        };
+        let udp_challenge = self.settings.udp_challenge;
+        let c = self.find_connection_by_token(token);
+        let sinks = c.get_sinks();
+        let verified = c.get_verified_sinks();
+        let peer = c.get_addr();
+        let burstable = sinks.iter()
+            .filter(|&sink| verified.contains(sink) || !udp_challenge && sink.0 == peer)
+            .cloned()
+            .collect();
+        debug!("sending message to add sinks: {:?}", sinks);
+        let action = Action::Add(token, sinks, burstable);
+        if self.stations[current_channel].channel.send(action).is_err() {
+            error!("station {} is gone", current_channel);
+        }
+    }
+
+    /// Handle a HELLO, answering with a WELCOME.
+    ///
+    /// `extension` holds the protocol version and capabilities advertised by an extended HELLO.
+    /// Those are negotiated down to what this server supports and echoed in the WELCOME.
+    fn hello(&mut self, token: Token, udp_port: u16, extension: Option<(u8, u16)>) {
+        info!("udp_port: {}", udp_port);
+        let requested = match extension {
+            Some((version, capabilities)) => (version.to_string(), capabilities.to_string()),
+            None => ("null".to_string(), "null".to_string()),
+        };
+        self.log_event("hello",
+                       token,
+                       &[("udp_port", udp_port.to_string()),
+                         ("version", requested.0),
+                         ("capabilities", requested.1)]);
+        if self.find_connection_by_token(token).is_handshake_done() {
+            console!("{:?}: re-received HELLO, sending INVALID_COMMAND; closing connection",
+                     token);
+            self.disconnect_with_invalid_command(token,
+                                                 "repeated_hello",
+                                                 "Handshake already done but server \
+                                                  re-received HELLO");
+            return;
+        }
+
+        if !self.valid_udp_port(udp_port) {
+            console!("{:?}: HELLO with invalid UDP port {}, sending INVALID_COMMAND; closing \
+                      connection",
+                     token,
+                     udp_port);
+            self.disconnect_with_invalid_command(token,
+                                                 "invalid_udp_port",
+                                                 "server received a HELLO with a UDP port that \
+                                                  is 0, privileged or its own port");
+            return;
+        }
+
+        self.find_connection_by_token(token).set_udp_port(udp_port);
+        console!("{:?}: HELLO received; sending WELCOME, expecting SET_STATION",
+                 token);
+        debug!("Station Count: {}", self.stations.len());
+        let mut welcomebuf: Vec<u8> = vec![0; 3];
+        BigEndian::write_u16(&mut welcomebuf[1..], self.stations.len() as u16);
+        // legacy clients speak version 0 without capabilities
+        let mut negotiated = (0, 0);
+        if let Some((version, capabilities)) = extension {
+            let version = cmp::min(version, PROTOCOL_VERSION);
+            let mut capabilities = capabilities & SERVER_CAPABILITIES;
+            if self.settings.auth_keys.is_empty() {
+                capabilities &= !CAP_AUTH;
+            }
+            let nonce = if capabilities & CAP_AUTH != 0 {
+                match auth::new_nonce() {
+                    Ok(nonce) => Some(nonce),
+                    Err(e) => {
+                        error!("Failed to generate a nonce for {:?}: {:?}", token, e);
+                        capabilities &= !CAP_AUTH;
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            console!("{:?}: negotiated protocol version {}, capabilities {:#06x}",
+                     token,
+                     version,
+                     capabilities);
+            welcomebuf[0] = 5; // reply_type
+            welcomebuf.push(version);
+            let mut capabilities_buf = [0u8; 2];
+            BigEndian::write_u16(&mut capabilities_buf, capabilities);
+            welcomebuf.extend_from_slice(&capabilities_buf);
+            self.find_connection_by_token(token).set_capabilities(capabilities);
+            if let Some(nonce) = nonce {
+                welcomebuf.extend_from_slice(&nonce);
+                self.find_connection_by_token(token).set_nonce(nonce);
+            }
+            negotiated = (version, capabilities);
+        }
+        debug!("{:?}", welcomebuf);
+        self.find_connection_by_token(token)
+            .send_message(Rc::new(welcomebuf))
+            .ok();
+        self.find_connection_by_token(token).mark_handshake_done();
+        self.find_connection_by_token(token).mark_pong();
+        self.metrics.handshakes += 1;
+        self.log_event("welcome",
+                       token,
+                       &[("num_stations", self.stations.len().to_string()),
+                         ("version", negotiated.0.to_string()),
+                         ("capabilities", negotiated.1.to_string())]);
+
+        let addr = self.find_connection_by_token(token).get_addr();
+        self.challenge(token, (addr, udp_port));
+    }
+
+    /// Whether audio may be sent to UDP port `port`.
+    ///
+    /// Port 0, privileged ports and the server's own port are refused, so that the server cannot
+    /// be pointed at well known services.
+    fn valid_udp_port(&self, port: u16) -> bool {
+        port >= 1024 && port != self.port
+    }
+
+    /// Send `sink` a UDP challenge, holding back its audio until the client echoes the cookie in
+    /// a VERIFY.
+    ///
+    /// Sinks at the client's own address are only challenged if challenges are enabled, while
+    /// sinks anywhere else always are, so that no client can point audio at a third party.
+    fn challenge(&mut self, token: Token, sink: UdpAddress) {
+        let peer = self.find_connection_by_token(token).get_addr();
+        if !self.settings.udp_challenge && sink.0 == peer {
+            return;
+        }
+        let cookie = match auth::new_cookie() {
+            Ok(cookie) => cookie,
+            Err(e) => {
+                error!("Failed to generate a cookie for {:?}: {:?}", token, e);
+                self.find_connection_by_token(token).mark_reset();
+                return;
+            }
+        };
+
+        console!("{:?}: sending UDP challenge to {}:{}", token, sink.0, sink.1);
+        self.send_challenge(sink, cookie);
+        self.find_connection_by_token(token).add_challenge(sink, cookie);
+    }
+
+    /// Send the datagram challenging `sink` to echo `cookie`.
+    fn send_challenge(&self, sink: UdpAddress, cookie: u64) {
+        let mut challengebuf = CHALLENGE_MAGIC.to_vec();
+        let mut cookie_buf = [0u8; 8];
+        BigEndian::write_u64(&mut cookie_buf, cookie);
+        challengebuf.extend_from_slice(&cookie_buf);
+        if let Some(ref limiter) = self.limiter {
+            if !limiter.lock().unwrap().allow(sink.0, challengebuf.len()) {
+                trace!("rate limit reached for {}, not sending UDP challenge", sink.0);
+                return;
+            }
+        }
+        let dest = SocketAddr::new(IpAddr::V4(sink.0), sink.1);
+        if let Err(e) = self.challenge_sock.send_to(&challengebuf, &dest) {
+            warn!("Failed to send UDP challenge to {}: {:?}", dest, e);
+        }
+    }
+
+    /// Send the challenges that are still unanswered again, as UDP may have lost them.
+    ///
+    /// Clients that leave a challenge unanswered after `MAX_CHALLENGE_SENDS` are disconnected,
+    /// so that the server cannot be used to keep sending datagrams to a host that never asked for
+    /// them.
+    fn resend_challenges(&mut self) {
+        let interval = Duration::from_millis(CHALLENGE_INTERVAL_MS);
+        let mut due = Vec::new();
+        let mut unanswered = Vec::new();
+        for c in self.conns.iter_mut() {
+            if c.is_reset() || c.is_to_be_removed() {
+                continue;
+            }
+            let (resend, given_up) = c.take_due_challenges(interval, MAX_CHALLENGE_SENDS);
+            due.extend(resend);
+            unanswered.extend(given_up.into_iter().map(|sink| (c.token, sink)));
+        }
+        for (sink, cookie) in due {
+            trace!("resending UDP challenge to {}:{}", sink.0, sink.1);
+            self.send_challenge(sink, cookie);
+        }
+        for (token, sink) in unanswered {
+            if self.find_connection_by_token(token).is_to_be_removed() {
+                continue;
+            }
+            console!("{:?}: UDP challenge to {}:{} went unanswered, sending INVALID_COMMAND; \
+                      closing connection",
+                     token,
+                     sink.0,
+                     sink.1);
+            self.find_connection_by_token(token).remove_sink(sink);
+            let reply = format!("UDP sink {}:{} did not answer its challenge", sink.0, sink.1);
+            self.disconnect_with_invalid_command(token, "challenge_unanswered", &reply);
+            // the INVALID_COMMAND needs a write interest, which `tick` registers for idle
+            // connections
+            self.find_connection_by_token(token).mark_idle();
+        }
+    }
+
     /// Forward a readable event to an established connection.
     ///
     /// Connections are identified by the token provided to us from the poller. Once a read has
@@ -348,87 +1047,254 @@ impl Server {
     fn readable(&mut self, token: Token) -> io::Result<()> {
         debug!("server conn readable; token={:?}", token);
 
-        while let Some(command) = try!(self.find_connection_by_token(token).readable()) {
+        while let Some(command) = self.find_connection_by_token(token).readable()? {
+            // whatever follows a command that got the connection closed is not answered, so
+            // that the INVALID_COMMAND is the last thing the client gets
+            if self.find_connection_by_token(token).is_to_be_removed() {
+                break;
+            }
+
+            let hello = matches!(command,
+                                 ServerCommand::Hello { .. } | ServerCommand::HelloExt { .. });
+            if !hello && !self.find_connection_by_token(token).is_handshake_done() {
+                console!("{:?}: received a command before HELLO, sending INVALID_COMMAND; \
+                          closing connection",
+                         token);
+                self.disconnect_with_invalid_command(token,
+                                                     "missing_hello",
+                                                     "server received a command before the \
+                                                      HELLO");
+                break;
+            }
+
+            let agreed = command.capability()
+                .is_none_or(|cap| self.find_connection_by_token(token).has_capability(cap));
+            if !agreed {
+                console!("{:?}: received {:?} without agreeing on its capability, sending \
+                          INVALID_COMMAND; closing connection",
+                         token,
+                         command);
+                self.disconnect_with_invalid_command(token,
+                                                     "capability_not_agreed",
+                                                     "server received a command whose \
+                                                      capability was not agreed on");
+                break;
+            }
+
             match command {
-                ServerCommand::Hello { udp_port, .. } => {
-                    info!("udp_port: {}", udp_port);
-                    if self.find_connection_by_token(token).is_handshake_done() {
-                        println!("{:?}: re-received HELLO, sending INVALID_COMMAND; closing \
-                                  connection",
-                                 token);
-                        self.disconnect_with_invalid_command(token,
-                                                             "Handshake already done but server \
-                                                              re-received HELLO");
-                    } else {
-                        self.find_connection_by_token(token).set_udp_port(udp_port);
-                        println!("{:?}: HELLO received; sending WELCOME, expecting SET_STATION",
-                                 token);
-                        let mut welcomebuf: Vec<u8> = vec![0; 3];
-                        unsafe {
-                            welcomebuf.set_len(3);
-                        }
-                        debug!("Station Count: {}", self.stations.len());
-                        BigEndian::write_u16(&mut welcomebuf[1..], self.stations.len() as u16);
-                        debug!("{:?}", welcomebuf);
-                        self.find_connection_by_token(token)
-                            .send_message(Rc::new(welcomebuf.to_vec()))
-                            .ok();
-                        self.find_connection_by_token(token).mark_handshake_done();
-                    }
+                ServerCommand::Hello { udp_port } => self.hello(token, udp_port, None),
+                ServerCommand::HelloExt { udp_port, version, capabilities } => {
+                    self.hello(token, udp_port, Some((version, capabilities)))
                 }
-                ServerCommand::SetStation { station_number, .. } => {
+                ServerCommand::SetStation { station_number } => {
                     let station_number = station_number as usize;
                     if station_number >= self.stations.len() {
-                        println!("{:?}: received request for invalid station: {}, \
+                        console!("{:?}: received request for invalid station: {}, \
                                   sending INVALID_COMMAND; closing connection",
                                  token,
                                  station_number);
                         self.disconnect_with_invalid_command(token,
+                                                             "invalid_station",
                                                              "server received a SET_STATION \
                                                               command with an invalid station \
                                                               number");
+                    } else if !self.authorized(token, station_number) {
+                        console!("{:?}: received request for restricted station: {}, \
+                                  sending INVALID_COMMAND; closing connection",
+                                 token,
+                                 station_number);
+                        let reply = format!("station {} requires authentication",
+                                            station_number);
+                        self.disconnect_with_invalid_command(token, "auth_required", &reply);
+                    } else if !self.settings
+                        .access_list
+                        .permits_station(self.conns[token].get_addr(), station_number as u16) {
+                        console!("{:?}: received request for station {} not available to it, \
+                                  sending INVALID_COMMAND; closing connection",
+                                 token,
+                                 station_number);
+                        let reply = format!("station {} is not available from {}",
+                                            station_number,
+                                            self.conns[token].get_addr());
+                        self.disconnect_with_invalid_command(token,
+                                                             "station_access_denied",
+                                                             &reply);
+                    } else if self.station_full(token, station_number) {
+                        console!("{:?}: received request for full station: {}, \
+                                  sending INVALID_COMMAND; closing connection",
+                                 token,
+                                 station_number);
+                        let reply = format!("station {} already has the maximum of {} listeners",
+                                            station_number,
+                                            self.settings.max_listeners.unwrap_or(0));
+                        self.disconnect_with_invalid_command(token, "station_full", &reply);
                     } else {
-                        println!("{:?}: received SET_STATION to station {}",
+                        console!("{:?}: received SET_STATION to station {}",
                                  token,
                                  station_number);
 
-                        let current_channel = self.find_connection_by_token(token)
-                            .get_current_channel();
-                        let ip = self.find_connection_by_token(token).get_addr();
-                        let udp_port = self.find_connection_by_token(token).get_udp_port();
-                        if current_channel < self.stations.len() as u16 {
-                            debug!("sending message to remove port: {}", udp_port);
-                            self.channels[current_channel as usize]
-                                .send(Action::Remove((ip, udp_port)))
-                                .unwrap();
-                        }
-                        self.channels[station_number].send(Action::Add((ip, udp_port))).unwrap();
+                        self.leave_station(token);
                         self.find_connection_by_token(token)
-                            .set_current_channel(station_number as u16);
+                            .set_current_channel(Some(station_number as u16));
+                        self.update_sinks(token);
+                        self.log_event("set_station", token, &[]);
 
-                        let song_name_size = self.stations[station_number].len();
-                        let mut announcebuf: Vec<u8> = vec![0; 2];
-                        unsafe {
-                            announcebuf.set_len(2);
-                        }
-                        announcebuf[0] = 1; // reply_type
-                        announcebuf[1] = song_name_size as u8;
-                        let song_name = self.stations[station_number].clone();
-                        debug!("song_name: {:?}", song_name);
-                        let mut song_name_vec = song_name.into_bytes();
-                        announcebuf.append(&mut song_name_vec);
+                        let song_name = self.stations[station_number]
+                            .now_playing
+                            .lock()
+                            .unwrap()
+                            .song_name
+                            .clone();
+                        let mut announcebuf = vec![1]; // reply_type
+                        push_string(&mut announcebuf, &song_name);
                         self.find_connection_by_token(token)
-                            .send_message(Rc::new(announcebuf.to_vec()))
+                            .send_message(Rc::new(announcebuf))
                             .ok();
-                        debug!("Sending songname: {}",
-                               String::from_utf8(announcebuf[2..].to_vec()).unwrap());
+                        debug!("Sending songname: {}", song_name);
+
+                        let health = self.stations[station_number]
+                            .now_playing
+                            .lock()
+                            .unwrap()
+                            .health;
+                        let c = self.find_connection_by_token(token);
+                        if health != Health::Online && c.has_capability(CAP_STATION_STATUS) {
+                            c.send_message(Rc::new(station_status(station_number as u16, health)))
+                                .ok();
+                        }
                     }
                 }
-                _ => {
-                    println!("{:?}: received unknown command type, sending INVALID_COMMAND; \
+                ServerCommand::ListStations => {
+                    console!("{:?}: received LIST_STATIONS; sending STATION_LIST", token);
+
+                    let with_status = self.find_connection_by_token(token)
+                        .has_capability(CAP_STATION_STATUS);
+                    let mut listbuf: Vec<u8> = vec![0; 3];
+                    listbuf[0] = 3; // reply_type
+                    BigEndian::write_u16(&mut listbuf[1..], self.stations.len() as u16);
+                    for (station_number, station) in self.stations.iter().enumerate() {
+                        let mut station_number_buf = [0u8; 2];
+                        BigEndian::write_u16(&mut station_number_buf, station_number as u16);
+                        listbuf.extend_from_slice(&station_number_buf);
+                        push_string(&mut listbuf, &station.name);
+                        let now_playing = station.now_playing.lock().unwrap();
+                        push_string(&mut listbuf, &now_playing.song_name);
+                        if with_status {
+                            listbuf.push(now_playing.health.status_byte());
+                        }
+                    }
+                    debug!("{:?}", listbuf);
+                    self.find_connection_by_token(token)
+                        .send_message(Rc::new(listbuf))
+                        .ok();
+                }
+                ServerCommand::NowPlaying { station_number } => {
+                    let station_number = station_number as usize;
+                    if station_number >= self.stations.len() {
+                        console!("{:?}: received NOW_PLAYING for invalid station: {}, \
+                                  sending INVALID_COMMAND; closing connection",
+                                 token,
+                                 station_number);
+                        self.disconnect_with_invalid_command(token,
+                                                             "invalid_station",
+                                                             "server received a NOW_PLAYING \
+                                                              command with an invalid station \
+                                                              number");
+                        continue;
+                    }
+
+                    console!("{:?}: received NOW_PLAYING for station {}", token, station_number);
+
+                    let mut nowplayingbuf: Vec<u8> = vec![0; 3];
+                    nowplayingbuf[0] = 4; // reply_type
+                    BigEndian::write_u16(&mut nowplayingbuf[1..], station_number as u16);
+                    {
+                        let now_playing = self.stations[station_number].now_playing.lock().unwrap();
+                        push_string(&mut nowplayingbuf, &now_playing.song_name);
+                        let mut times = [0u8; 8];
+                        BigEndian::write_u32(&mut times[..4], now_playing.elapsed_ms());
+                        BigEndian::write_u32(&mut times[4..], now_playing.duration_ms());
+                        nowplayingbuf.extend_from_slice(&times);
+                    }
+                    debug!("{:?}", nowplayingbuf);
+                    self.find_connection_by_token(token)
+                        .send_message(Rc::new(nowplayingbuf))
+                        .ok();
+                }
+                ServerCommand::Stop => {
+                    // STOP has no reply
+                    console!("{:?}: received STOP", token);
+                    self.leave_station(token);
+                }
+                ServerCommand::Quit => {
+                    console!("{:?}: received QUIT; sending GOODBYE, closing connection", token);
+                    self.leave_station(token);
+                    let goodbyebuf: Vec<u8> = vec![7, 0, 0]; // reply_type, unused
+                    self.find_connection_by_token(token)
+                        .send_message(Rc::new(goodbyebuf))
+                        .ok();
+                    self.find_connection_by_token(token).mark_to_be_removed();
+                }
+                ServerCommand::AddSink { addr, udp_port } => {
+                    console!("{:?}: received ADD_SINK for {}:{}", token, addr, udp_port);
+                    if !self.valid_udp_port(udp_port) {
+                        console!("{:?}: invalid sink port, sending INVALID_COMMAND; closing \
+                                  connection",
+                                 token);
+                        self.disconnect_with_invalid_command(token,
+                                                             "invalid_udp_port",
+                                                             "server received an ADD_SINK \
+                                                              command with a UDP port that is \
+                                                              0, privileged or its own port");
+                        continue;
+                    }
+                    // sinks already sent the audio or a challenge are not challenged again
+                    let known = {
+                        let c = self.find_connection_by_token(token);
+                        let sink = (addr, udp_port);
+                        c.get_sinks().contains(&sink) || c.is_challenged(sink)
+                    };
+                    if !self.find_connection_by_token(token).add_sink((addr, udp_port)) {
+                        console!("{:?}: too many sinks, sending INVALID_COMMAND; closing \
+                                  connection",
+                                 token);
+                        self.disconnect_with_invalid_command(token,
+                                                             "too_many_sinks",
+                                                             "server received an ADD_SINK \
+                                                              command beyond the sink limit");
+                        continue;
+                    }
+                    if !known {
+                        self.challenge(token, (addr, udp_port));
+                    }
+                    self.update_sinks(token);
+                }
+                ServerCommand::RemoveSink { addr, udp_port } => {
+                    console!("{:?}: received REMOVE_SINK for {}:{}", token, addr, udp_port);
+                    self.find_connection_by_token(token).remove_sink((addr, udp_port));
+                    self.update_sinks(token);
+                }
+                ServerCommand::Auth { key_name, mac } => self.authenticate(token, &key_name, &mac),
+                ServerCommand::Verify { cookie } => {
+                    match self.find_connection_by_token(token).verify(cookie) {
+                        Some((addr, udp_port)) => {
+                            console!("{:?}: received VERIFY for {}:{}", token, addr, udp_port);
+                            self.update_sinks(token);
+                        }
+                        None => console!("{:?}: received VERIFY with an unknown cookie", token),
+                    }
+                }
+                ServerCommand::Pong { sequence } => {
+                    trace!("{:?}: received PONG {}", token, sequence);
+                    self.find_connection_by_token(token).mark_pong();
+                }
+                ServerCommand::Invalid { command_type } => {
+                    console!("{:?}: received unknown command type {}, sending INVALID_COMMAND; \
                               closing connection",
-                             token);
+                             token,
+                             command_type);
                     self.disconnect_with_invalid_command(token,
+                                                         "unknown_command",
                                                          "server received an unknown command");
                 }
             }
@@ -438,7 +1304,7 @@ impl Server {
     }
 
     /// Find a connection in the slab using the given token.
-    fn find_connection_by_token<'a>(&'a mut self, token: Token) -> &'a mut Connection {
+    fn find_connection_by_token(&mut self, token: Token) -> &mut Connection {
         &mut self.conns[token]
     }
 }
@@ -0,0 +1,171 @@
+// silent frames are MPEG-1 Layer III at this sample rate, in mono
+const SAMPLE_RATE: u32 = 44_100;
+
+// a Layer III frame holds 1152 samples, so it takes 144000 * kbps / sample rate bytes
+const BYTES_PER_KBPS: u32 = 144_000;
+
+// MPEG-1 Layer III bitrates in kbit/s, by the index the header encodes them as
+const BITRATES: [u32; 14] = [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+
+/// An endless stream of silent MP3 frames, without needing an encoder.
+///
+/// Every frame has all-zero side info and main data, which decoders play as silence. Frames are
+/// padded by a byte now and then, as encoders do, to keep exactly to the bitrate.
+pub struct Silence {
+    bitrate_kbps: u32,
+
+    // index of the bitrate in the header
+    bitrate_index: u8,
+
+    // the frame being read and how much of it was read
+    frame: Vec<u8>,
+    offset: usize,
+
+    // what is left of BYTES_PER_KBPS * kbps / SAMPLE_RATE, summed over the frames so far
+    remainder: u32,
+}
+
+impl Silence {
+    /// A stream at `bitrate_kbps`, which must be an MPEG-1 Layer III bitrate.
+    pub fn new(bitrate_kbps: u32) -> Option<Silence> {
+        let bitrate_index = BITRATES.iter().position(|&kbps| kbps == bitrate_kbps)? + 1;
+        Some(Silence {
+            bitrate_kbps,
+            bitrate_index: bitrate_index as u8,
+            frame: Vec::new(),
+            offset: 0,
+            remainder: 0,
+        })
+    }
+
+    /// Bytes the stream takes per second of audio.
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bitrate_kbps as u64 * 1000 / 8
+    }
+
+    /// Build the next frame.
+    pub fn next_frame(&mut self) -> Vec<u8> {
+        let bits = BYTES_PER_KBPS * self.bitrate_kbps;
+        self.remainder += bits % SAMPLE_RATE;
+        let padding = self.remainder >= SAMPLE_RATE;
+        if padding {
+            self.remainder -= SAMPLE_RATE;
+        }
+        let len = (bits / SAMPLE_RATE) as usize + padding as usize;
+
+        let mut frame = vec![0u8; len];
+        // sync word, MPEG-1, Layer III, no CRC
+        frame[0] = 0xff;
+        frame[1] = 0xfb;
+        // bitrate, 44.1kHz, padding
+        frame[2] = self.bitrate_index << 4 | (padding as u8) << 1;
+        // mono, leaving the side info and main data that follow all zeros
+        frame[3] = 0xc0;
+        frame
+    }
+
+    /// Fill `buf` with the next bytes of the stream, which runs on from frame to frame.
+    pub fn read(&mut self, buf: &mut [u8]) {
+        let mut filled = 0;
+        while filled < buf.len() {
+            if self.offset == self.frame.len() {
+                self.frame = self.next_frame();
+                self.offset = 0;
+            }
+            let n = (buf.len() - filled).min(self.frame.len() - self.offset);
+            buf[filled..filled + n].copy_from_slice(&self.frame[self.offset..self.offset + n]);
+            filled += n;
+            self.offset += n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use timeshift::frame_len;
+
+    // frames in a little over ten seconds of audio
+    const FRAMES: usize = 400;
+
+    /// The lengths of the frames `audio` is made of, failing on anything that is not one.
+    fn frame_lens(mut audio: &[u8]) -> Vec<usize> {
+        let mut lens = Vec::new();
+        while !audio.is_empty() {
+            let len = frame_len(audio).expect("not a frame");
+            assert!(len <= audio.len(), "truncated frame");
+            lens.push(len);
+            audio = &audio[len..];
+        }
+        lens
+    }
+
+    #[test]
+    fn only_takes_mpeg1_layer3_bitrates() {
+        for &kbps in &[0, 8, 33, 144, 384] {
+            assert!(Silence::new(kbps).is_none(), "{} kbit/s", kbps);
+        }
+    }
+
+    #[test]
+    fn bytes_per_sec_follows_the_bitrate() {
+        for &kbps in BITRATES.iter() {
+            assert_eq!(Silence::new(kbps).unwrap().bytes_per_sec(), kbps as u64 * 125);
+        }
+    }
+
+    #[test]
+    fn frames_parse_at_every_bitrate() {
+        for &kbps in BITRATES.iter() {
+            let mut silence = Silence::new(kbps).unwrap();
+            let frames: Vec<Vec<u8>> = (0..FRAMES).map(|_| silence.next_frame()).collect();
+            for frame in &frames {
+                assert_eq!(frame_len(frame), Some(frame.len()), "{} kbit/s", kbps);
+                assert!(frame[4..].iter().all(|&b| b == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn padding_keeps_exactly_to_the_bitrate() {
+        for &kbps in BITRATES.iter() {
+            let mut silence = Silence::new(kbps).unwrap();
+            let bits = BYTES_PER_KBPS as u64 * kbps as u64;
+            let mut total = 0;
+            for frames in 1..FRAMES as u64 + 1 {
+                let frame = silence.next_frame();
+                let padded = frame[2] & 0x02 != 0;
+                assert_eq!(frame.len() as u64, bits / SAMPLE_RATE as u64 + padded as u64);
+                total += frame.len() as u64;
+                // never more than a byte short of where the bitrate says
+                assert_eq!(total, frames * bits / SAMPLE_RATE as u64, "{} kbit/s", kbps);
+            }
+        }
+    }
+
+    #[test]
+    fn read_runs_on_from_frame_to_frame() {
+        for &kbps in BITRATES.iter() {
+            let mut reference = Silence::new(kbps).unwrap();
+            let expected: Vec<u8> = (0..FRAMES).flat_map(|_| reference.next_frame()).collect();
+
+            // in pieces that end anywhere in a frame
+            let mut silence = Silence::new(kbps).unwrap();
+            let mut audio = vec![0u8; expected.len()];
+            for chunk in audio.chunks_mut(1000) {
+                silence.read(chunk);
+            }
+            assert!(audio == expected, "{} kbit/s", kbps);
+
+            let lens = frame_lens(&audio);
+            assert_eq!(lens.len(), FRAMES);
+            // 1152 samples a frame
+            let secs = (FRAMES * 1152) as f64 / SAMPLE_RATE as f64;
+            let kbps_measured = audio.len() as f64 * 8.0 / secs / 1000.0;
+            assert!((kbps_measured - kbps as f64).abs() < 0.01,
+                    "{} kbit/s measured as {}",
+                    kbps,
+                    kbps_measured);
+        }
+    }
+}
@@ -0,0 +1,1249 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Error, ErrorKind, Read};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+use mio::net::UdpSocket;
+
+use archive::Archive;
+use connection::UdpAddress;
+use metrics::StationMetrics;
+use ratelimit::RateLimiter;
+use schedule::{self, Clock, Schedule, SystemClock};
+use relay::{self, Received, Relay};
+use silence::Silence;
+use timeshift::TimeShift;
+
+// every station sends PACKET_SIZE bytes every PACKET_INTERVAL_NS, i.e. 16KiB/s or 128kbit/s
+pub const PACKET_SIZE: usize = 1024;
+pub const PACKET_INTERVAL_NS: u32 = 62_500_000;
+pub const BYTES_PER_SEC: u64 = PACKET_SIZE as u64 * 1_000_000_000 / PACKET_INTERVAL_NS as u64;
+
+// a primary source that failed is tried again after this many seconds, doubling up to the
+// maximum while it keeps failing
+const MIN_RETRY_BACKOFF_SECS: u64 = 1;
+const MAX_RETRY_BACKOFF_SECS: u64 = 60;
+
+// how long tapping another station waits for it to say what it plays
+const TAP_TIMEOUT_MS: u64 = 1000;
+
+// a live source that sends nothing for this long is dropped
+const LIVE_TIMEOUT_SECS: u64 = 10;
+
+// a host is sent at most one burst of the time shift this often, however many sinks it joins with
+const BURST_INTERVAL_SECS: u64 = 30;
+
+/// Changes to the recipients of a station, which are tracked per control connection.
+pub enum Action {
+    // send to all of these sinks of the connection, replacing the ones it had before; only
+    // those also in the second list are known to receive there and may be sent a burst
+    Add(Token, Vec<UdpAddress>, Vec<UdpAddress>),
+    // stop sending to any of the sinks of the connection
+    Remove(Token),
+    // pass everything the station plays on to the numbered station, until it hangs up
+    Tap(u16, Sender<Feed>),
+    // play the audio an encoder pushes on the stream, under the given name, replacing any
+    // encoder that pushed before
+    Live(TcpStream, String),
+    // the encoder says it now plays the named song
+    Metadata(String),
+}
+
+/// What a station passes on to the stations tapping it.
+pub enum Feed {
+    Audio(Vec<u8>),
+    SongChange(String),
+}
+
+/// Whether a station plays, and from which of its sources.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    // neither source works, so the station plays silence
+    Offline,
+    // playing the primary source
+    Online,
+    // playing the fallback source until the primary recovers
+    Fallback,
+}
+
+impl Health {
+    /// The status byte sent in STATION_LIST and STATION_STATUS.
+    pub fn status_byte(self) -> u8 {
+        match self {
+            Health::Offline => 0,
+            Health::Online => 1,
+            Health::Fallback => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Offline => "offline",
+            Health::Online => "online",
+            Health::Fallback => "fallback",
+        }
+    }
+}
+
+/// Something that happened on a station thread, passed on to the event loop.
+pub enum StationEvent {
+    // the station started playing the named song, which listeners are to be announced if set
+    SongChange(u16, String, bool),
+    // a source of the station failed, as described by the message
+    Error(u16, String),
+    // the station switched sources or went offline; it now plays the named song
+    HealthChange(u16, Health, String),
+    // sending to the sink failed, as described by the message, so it was dropped
+    SinkFailed(u16, UdpAddress, String),
+}
+
+/// What a station is playing right now, kept up to date by its thread.
+pub struct NowPlaying {
+    pub song_name: String,
+
+    // bytes of the song streamed so far
+    pub offset: u64,
+
+    // size of the song in bytes, 0 if unknown
+    pub length: u64,
+
+    pub health: Health,
+}
+
+impl NowPlaying {
+    pub fn new(song_name: String) -> NowPlaying {
+        NowPlaying {
+            song_name,
+            offset: 0,
+            length: 0,
+            health: Health::Online,
+        }
+    }
+
+    /// How far into the song the station is, in milliseconds.
+    pub fn elapsed_ms(&self) -> u32 {
+        (self.offset * 1000 / BYTES_PER_SEC) as u32
+    }
+
+    /// How long the whole song plays, in milliseconds.
+    pub fn duration_ms(&self) -> u32 {
+        (self.length * 1000 / BYTES_PER_SEC) as u32
+    }
+}
+
+/// Where a station gets its audio from.
+#[derive(Clone)]
+pub enum SourceSpec {
+    // an MP3 file, played over and over
+    File(String),
+    // silent MP3 frames
+    Silence,
+    // whatever the numbered station plays
+    Station(u16),
+    // whatever an encoder pushes to the ingest port
+    Live,
+    // a station of another rustcast server, at `host:port`
+    Relay(String, u16),
+    // the playlists of the schedule in the file, each in its time slot
+    Schedule(String),
+}
+
+impl SourceSpec {
+    /// Parse `silence`, `live`, `station:<number>`, `relay:<host>:<port>/<station>`,
+    /// `schedule:<file>` or else the path of a file.
+    pub fn parse(s: &str) -> Option<SourceSpec> {
+        if s == "silence" {
+            Some(SourceSpec::Silence)
+        } else if s == "live" {
+            Some(SourceSpec::Live)
+        } else if let Some(number) = s.strip_prefix("station:") {
+            number.parse().ok().map(SourceSpec::Station)
+        } else if let Some(upstream) = s.strip_prefix("relay:") {
+            relay::parse_upstream(upstream).map(|(addr, number)| SourceSpec::Relay(addr, number))
+        } else if let Some(path) = s.strip_prefix("schedule:") {
+            Some(SourceSpec::Schedule(path.to_string()))
+        } else {
+            Some(SourceSpec::File(s.to_string()))
+        }
+    }
+}
+
+impl fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SourceSpec::File(ref path) => write!(f, "{}", path),
+            SourceSpec::Silence => write!(f, "silence"),
+            SourceSpec::Station(number) => write!(f, "station {}", number),
+            SourceSpec::Live => write!(f, "live"),
+            SourceSpec::Relay(ref addr, number) => write!(f, "relay {}/{}", addr, number),
+            SourceSpec::Schedule(ref path) => write!(f, "schedule {}", path),
+        }
+    }
+}
+
+/// The sources a station plays from and where it is archived.
+pub struct StationConfig {
+    pub primary: SourceSpec,
+
+    // played while the primary source fails, if any
+    pub fallback: Option<SourceSpec>,
+
+    // played between the files the station plays, if any
+    pub jingles: Option<Jingles>,
+
+    // directory to archive everything the station sends to, if any
+    pub archive: Option<PathBuf>,
+}
+
+impl StationConfig {
+    /// The stations this one plays, as its primary source or its fallback.
+    fn plays(&self) -> impl Iterator<Item = usize> + '_ {
+        Some(&self.primary).into_iter().chain(self.fallback.as_ref()).filter_map(|source| {
+            match *source {
+                SourceSpec::Station(other) => Some(other as usize),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Stations that end up playing themselves through one another, such as `[0, 1, 0]` when
+/// station 0 plays station 1 which falls back to station 0, if any.
+///
+/// Every station another one plays must exist.
+pub fn find_cycle(configs: &[StationConfig]) -> Option<Vec<usize>> {
+    // stations whose every path was followed to its end without coming back
+    let mut done = vec![false; configs.len()];
+    for first in 0..configs.len() {
+        // the path followed, with the stations left to follow from each station on it
+        let mut path = vec![first];
+        let mut pending = vec![configs[first].plays().collect::<Vec<_>>()];
+        while let Some(next) = pending.last_mut() {
+            let station = match next.pop() {
+                Some(station) => station,
+                None => {
+                    done[path.pop().unwrap()] = true;
+                    pending.pop();
+                    continue;
+                }
+            };
+            if let Some(start) = path.iter().position(|&s| s == station) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(station);
+                return Some(cycle);
+            }
+            if !done[station] {
+                path.push(station);
+                pending.push(configs[station].plays().collect());
+            }
+        }
+    }
+    None
+}
+
+/// Short clips, such as station IDs, played in turn between the files a station plays.
+#[derive(Clone)]
+pub struct Jingles {
+    pub files: Vec<PathBuf>,
+    pub every: JingleInterval,
+
+    // whether listeners are announced the clips, rather than only the files
+    pub announce: bool,
+}
+
+/// How often a jingle plays, always waiting for the file playing to end.
+#[derive(Clone, Copy)]
+pub enum JingleInterval {
+    // after this many files
+    Files(u32),
+    // once this many minutes passed since the last one
+    Minutes(u64),
+}
+
+/// Everything a station thread shares with the rest of the server.
+pub struct StationContext {
+    pub number: u16,
+    pub config: StationConfig,
+    pub now_playing: Arc<Mutex<NowPlaying>>,
+    pub limiter: Option<Arc<Mutex<RateLimiter>>>,
+    pub metrics: Arc<StationMetrics>,
+    pub events: Sender<StationEvent>,
+
+    // bitrate of the silence played by silent sources and while the station is offline
+    pub silence_bitrate: u32,
+
+    // how much of the audio just sent new recipients are sent at once, if any
+    pub time_shift: Option<Duration>,
+
+    // channels of all stations, to tap the one this station falls back to
+    pub stations: Vec<Sender<Action>>,
+}
+
+/// What a source produced for the station to send.
+enum Chunk {
+    // this many bytes of audio were read into the buffer
+    Audio(usize),
+    // a new song of this name and length in bytes, 0 if unknown, starts, which listeners are
+    // to be announced if set
+    Song(String, u64, bool),
+    // there is nothing to send this time
+    Nothing,
+}
+
+/// Silence paced to its bitrate rather than to PACKET_SIZE bytes per packet.
+struct SilenceSource {
+    silence: Silence,
+
+    // nanoseconds worth of bytes not sent yet, times bytes per second
+    carry: u64,
+}
+
+impl SilenceSource {
+    fn new(bitrate_kbps: u32) -> SilenceSource {
+        SilenceSource {
+            silence: Silence::new(bitrate_kbps).expect("invalid silence bitrate"),
+            carry: 0,
+        }
+    }
+
+    /// Read a packet interval's worth of silence into `buffer`, returning its length.
+    fn next(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+        self.carry += self.silence.bytes_per_sec() * PACKET_INTERVAL_NS as u64;
+        let len = cmp::min((self.carry / 1_000_000_000) as usize, PACKET_SIZE);
+        self.carry -= len as u64 * 1_000_000_000;
+        self.silence.read(&mut buffer[..len]);
+        len
+    }
+}
+
+/// Files played one after the other, over and over, with jingles between them if the station
+/// has any.
+struct Playlist {
+    files: Vec<PathBuf>,
+
+    // the file playing, its index and its length
+    index: usize,
+    file: File,
+    length: u64,
+
+    jingles: Option<Jingles>,
+
+    // the jingle playing after the file, if one is
+    jingle: Option<File>,
+
+    // index of the jingle to play next
+    next_jingle: usize,
+
+    // files played since the last jingle, and when that one started
+    files_since_jingle: u32,
+    last_jingle: Instant,
+}
+
+impl Playlist {
+    fn open(files: Vec<PathBuf>, jingles: Option<Jingles>) -> io::Result<Playlist> {
+        let file = File::open(&files[0])?;
+        let length = file.metadata()?.len();
+        Ok(Playlist {
+            files,
+            index: 0,
+            file,
+            length,
+            jingles,
+            jingle: None,
+            next_jingle: 0,
+            files_since_jingle: 0,
+            last_jingle: Instant::now(),
+        })
+    }
+
+    fn song(&self) -> (String, u64) {
+        (song_name(&self.files[self.index]), self.length)
+    }
+
+    /// Read what to send next, moving on to a jingle, if one is due, or the next file at the
+    /// end of one.
+    fn next(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> io::Result<Chunk> {
+        if let Some(ref mut jingle) = self.jingle {
+            if let n @ 1.. = jingle.read(&mut buffer[..])? {
+                return Ok(Chunk::Audio(n));
+            }
+            self.jingle = None;
+            // listeners who were announced the jingle are announced the file after it too
+            let announce = self.jingles.as_ref().is_some_and(|jingles| jingles.announce);
+            return self.advance(announce);
+        }
+
+        match self.file.read(&mut buffer[..])? {
+            0 => {
+                self.files_since_jingle += 1;
+                let due = self.jingles.as_ref().is_some_and(|jingles| match jingles.every {
+                    JingleInterval::Files(files) => self.files_since_jingle >= files,
+                    JingleInterval::Minutes(minutes) => {
+                        let interval = minutes.checked_mul(60).map(Duration::from_secs);
+                        interval.is_some_and(|interval| self.last_jingle.elapsed() >= interval)
+                    }
+                });
+                match self.jingles {
+                    Some(ref jingles) if due => {
+                        let path = &jingles.files[self.next_jingle];
+                        let jingle = File::open(path)?;
+                        let length = jingle.metadata()?.len();
+                        self.jingle = Some(jingle);
+                        self.next_jingle = (self.next_jingle + 1) % jingles.files.len();
+                        self.files_since_jingle = 0;
+                        self.last_jingle = Instant::now();
+                        if jingles.announce {
+                            Ok(Chunk::Song(song_name(path), length, true))
+                        } else {
+                            // hidden jingles leave what the station says it plays alone, and
+                            // their audio follows the file's without a gap
+                            self.next(buffer)
+                        }
+                    }
+                    _ => self.advance(false),
+                }
+            }
+            n => Ok(Chunk::Audio(n)),
+        }
+    }
+
+    /// Move on to the next file, announcing it if it is another one or `announce` is set.
+    fn advance(&mut self, announce: bool) -> io::Result<Chunk> {
+        self.index = (self.index + 1) % self.files.len();
+        self.file = File::open(&self.files[self.index])?;
+        self.length = self.file.metadata()?.len();
+        // a file played over again is not worth announcing
+        let (song_name, length) = self.song();
+        Ok(Chunk::Song(song_name, length, announce || self.files.len() > 1))
+    }
+}
+
+/// Playlists switched between as a schedule says, with a jingle at the top of the hour.
+struct Scheduled {
+    schedule: Schedule,
+    clock: Box<dyn Clock>,
+
+    // played between the files of every playlist, if any
+    jingles: Option<Jingles>,
+
+    // the slot playing, `None` for the default playlist
+    program: Option<usize>,
+    playlist: Playlist,
+
+    // the jingle playing over the playlist, if it is
+    jingle: Option<File>,
+
+    // the hour the last jingle played in
+    jingle_hour: u64,
+}
+
+impl Scheduled {
+    /// Start playing `schedule` at the time `clock` says, holding off the jingle until the next
+    /// hour.
+    fn open(schedule: Schedule,
+            jingles: Option<Jingles>,
+            clock: Box<dyn Clock>)
+            -> io::Result<Scheduled> {
+        let now = clock.now();
+        let program = schedule.program_at(&now);
+        let playlist = Playlist::open(schedule.playlist(program).to_vec(), jingles.clone())?;
+        Ok(Scheduled {
+            schedule,
+            clock,
+            jingles,
+            program,
+            playlist,
+            jingle: None,
+            jingle_hour: now.hour,
+        })
+    }
+
+    /// Read what to send next, switching playlists or starting the jingle first if it is time
+    /// to, and announcing either.
+    fn next(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> io::Result<Chunk> {
+        let now = self.clock.now();
+        if let Some(ref path) = self.schedule.jingle {
+            if self.jingle.is_none() && now.hour != self.jingle_hour {
+                self.jingle_hour = now.hour;
+                let jingle = File::open(path)?;
+                let length = jingle.metadata()?.len();
+                self.jingle = Some(jingle);
+                return Ok(Chunk::Song(song_name(path), length, true));
+            }
+        }
+        if let Some(ref mut jingle) = self.jingle {
+            if let n @ 1.. = jingle.read(&mut buffer[..])? {
+                return Ok(Chunk::Audio(n));
+            }
+        }
+        let jingle_ended = self.jingle.take().is_some();
+
+        let program = self.schedule.program_at(&now);
+        if program != self.program {
+            self.program = program;
+            let files = self.schedule.playlist(program).to_vec();
+            self.playlist = Playlist::open(files, self.jingles.clone())?;
+            let (song_name, length) = self.playlist.song();
+            return Ok(Chunk::Song(song_name, length, true));
+        }
+        if jingle_ended {
+            // the playlist carries on where the jingle interrupted it
+            let (song_name, length) = self.playlist.song();
+            return Ok(Chunk::Song(song_name, length, true));
+        }
+        self.playlist.next(buffer)
+    }
+}
+
+/// The song name of a file in a playlist.
+fn song_name(path: &Path) -> String {
+    path.display().to_string()
+}
+
+/// An open source of audio.
+enum Source {
+    Playlist(Playlist),
+    Silence(SilenceSource),
+    Station { number: u16, feed: Receiver<Feed>, song_name: String },
+    Live { stream: TcpStream, song_name: String, last_data: Instant },
+    Relay(Relay),
+    Schedule(Scheduled),
+}
+
+impl Source {
+    fn open(spec: &SourceSpec, station: &StationContext) -> io::Result<Source> {
+        match *spec {
+            SourceSpec::File(ref path) => {
+                let jingles = station.config.jingles.clone();
+                Ok(Source::Playlist(Playlist::open(vec![PathBuf::from(path)], jingles)?))
+            }
+            SourceSpec::Silence => {
+                Ok(Source::Silence(SilenceSource::new(station.silence_bitrate)))
+            }
+            SourceSpec::Station(number) => {
+                let (tx, feed) = mpsc::channel();
+                station.stations
+                    .get(number as usize)
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such station"))?
+                    .send(Action::Tap(station.number, tx))
+                    .map_err(|_| Error::new(ErrorKind::BrokenPipe, "station is gone"))?;
+                // the tapped station starts by telling what it plays
+                let song_name = match feed.recv_timeout(Duration::from_millis(TAP_TIMEOUT_MS)) {
+                    Ok(Feed::SongChange(song_name)) => song_name,
+                    _ => spec.to_string(),
+                };
+                Ok(Source::Station {
+                    number,
+                    feed,
+                    song_name,
+                })
+            }
+            SourceSpec::Live => Err(Error::new(ErrorKind::NotConnected, "no encoder connected")),
+            SourceSpec::Relay(ref addr, number) => {
+                let timeout = Duration::new(0, PACKET_INTERVAL_NS) * 2;
+                Ok(Source::Relay(Relay::connect(addr, number, timeout)?))
+            }
+            SourceSpec::Schedule(ref path) => {
+                let schedule = schedule::load(path)?;
+                let jingles = station.config.jingles.clone();
+                Ok(Source::Schedule(Scheduled::open(schedule, jingles, Box::new(SystemClock))?))
+            }
+        }
+    }
+
+    /// Play what an encoder pushes on `stream`.
+    fn live(stream: TcpStream, song_name: String) -> io::Result<Source> {
+        stream.set_read_timeout(Some(Duration::new(0, PACKET_INTERVAL_NS) * 2))?;
+        Ok(Source::Live {
+            stream,
+            song_name,
+            last_data: Instant::now(),
+        })
+    }
+
+    /// The song the source starts with and its length in bytes, 0 if unknown.
+    fn song(&self) -> (String, u64) {
+        match *self {
+            Source::Playlist(ref playlist) => playlist.song(),
+            Source::Silence(_) => ("silence".to_string(), 0),
+            Source::Station { ref song_name, .. } |
+            Source::Live { ref song_name, .. } => (song_name.clone(), 0),
+            Source::Relay(ref relay) => (relay.song_name().to_string(), 0),
+            Source::Schedule(ref scheduled) => scheduled.playlist.song(),
+        }
+    }
+
+    /// Whether the station has to wait between packets, rather than the source setting the pace.
+    fn paced(&self) -> bool {
+        !matches!(*self, Source::Station { .. } | Source::Live { .. } | Source::Relay(_))
+    }
+
+    /// Read what to send next, wrapping around at the end of a file.
+    fn next(&mut self, buffer: &mut [u8; PACKET_SIZE]) -> io::Result<Chunk> {
+        match *self {
+            Source::Playlist(ref mut playlist) => playlist.next(buffer),
+            Source::Silence(ref mut silence) => Ok(Chunk::Audio(silence.next(buffer))),
+            Source::Station { number, ref feed, .. } => {
+                match feed.recv_timeout(Duration::new(0, PACKET_INTERVAL_NS) * 2) {
+                    Ok(Feed::Audio(audio)) => {
+                        let len = cmp::min(audio.len(), PACKET_SIZE);
+                        buffer[..len].copy_from_slice(&audio[..len]);
+                        Ok(Chunk::Audio(len))
+                    }
+                    Ok(Feed::SongChange(song_name)) => Ok(Chunk::Song(song_name, 0, false)),
+                    Err(RecvTimeoutError::Timeout) => Ok(Chunk::Nothing),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        Err(Error::new(ErrorKind::BrokenPipe,
+                                       format!("station {} stopped passing on audio", number)))
+                    }
+                }
+            }
+            Source::Live { ref mut stream, ref mut last_data, .. } => {
+                match stream.read(&mut buffer[..]) {
+                    Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "encoder disconnected")),
+                    Ok(n) => {
+                        *last_data = Instant::now();
+                        Ok(Chunk::Audio(n))
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock ||
+                                  e.kind() == ErrorKind::TimedOut => {
+                        if last_data.elapsed() > Duration::from_secs(LIVE_TIMEOUT_SECS) {
+                            Err(Error::new(ErrorKind::TimedOut, "encoder stopped sending"))
+                        } else {
+                            Ok(Chunk::Nothing)
+                        }
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Source::Relay(ref mut relay) => {
+                match relay.receive(&mut buffer[..])? {
+                    Received::Audio(len) => Ok(Chunk::Audio(len)),
+                    // the upstream server announced it to its listeners, so this one does too
+                    Received::Announce(song_name) => Ok(Chunk::Song(song_name, 0, true)),
+                    Received::Nothing => Ok(Chunk::Nothing),
+                }
+            }
+            Source::Schedule(ref mut scheduled) => scheduled.next(buffer),
+        }
+    }
+}
+
+/// Sends what a station plays to its recipients and the stations tapping it.
+struct Broadcaster {
+    station: StationContext,
+    recipients: HashMap<Token, Vec<UdpAddress>>,
+    taps: HashMap<u16, Sender<Feed>>,
+
+    // an encoder that connected and the song it plays, until the station switches to it
+    live: Option<(TcpStream, String)>,
+
+    // a song the encoder announced, until the station takes it up
+    metadata: Option<String>,
+
+    // the audio just sent, if new recipients get a burst of it
+    time_shift: Option<TimeShift>,
+
+    // sinks that were added since the last packet and still need their burst
+    joined: Vec<UdpAddress>,
+
+    // when hosts were last sent a burst, as they only get one every BURST_INTERVAL_SECS
+    bursts: HashMap<Ipv4Addr, Instant>,
+
+    // where everything sent is recorded, if anywhere
+    archive: Option<Archive>,
+}
+
+impl Broadcaster {
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Add(token, sinks, burstable) => {
+                debug!("adding: {:?} {:?}", token, sinks);
+                if self.time_shift.is_some() {
+                    for sink in &burstable {
+                        if !self.recipients.values().any(|sinks| sinks.contains(sink)) {
+                            self.joined.push(*sink);
+                        }
+                    }
+                }
+                self.recipients.insert(token, sinks);
+            }
+            Action::Remove(token) => {
+                debug!("removing: {:?}", token);
+                self.recipients.remove(&token);
+            }
+            Action::Tap(number, tx) => {
+                debug!("tapped by station {}", number);
+                let song_name = self.station.now_playing.lock().unwrap().song_name.clone();
+                if tx.send(Feed::SongChange(song_name)).is_ok() {
+                    self.taps.insert(number, tx);
+                }
+            }
+            Action::Live(stream, song_name) => {
+                debug!("live source connected: {}", song_name);
+                self.live = Some((stream, song_name));
+            }
+            Action::Metadata(song_name) => {
+                debug!("live source metadata: {}", song_name);
+                self.metadata = Some(song_name);
+            }
+        }
+    }
+
+    /// Apply every pending action, returning false once the server went away.
+    fn drain(&mut self, rx: &Receiver<Action>) -> bool {
+        loop {
+            match rx.try_recv() {
+                Ok(action) => self.apply(action),
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        }
+    }
+
+    /// Apply actions as they come in for `duration`, returning false once the server went away.
+    fn wait(&mut self, rx: &Receiver<Action>, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match rx.recv_timeout(timeout) {
+                Ok(action) => self.apply(action),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return false,
+            }
+        }
+        true
+    }
+
+    fn error(&self, message: String) {
+        self.station.events.send(StationEvent::Error(self.station.number, message)).ok();
+    }
+
+    fn set_song(&mut self, song_name: String, length: u64, announce: bool) {
+        {
+            let mut now_playing = self.station.now_playing.lock().unwrap();
+            now_playing.song_name = song_name.clone();
+            now_playing.offset = 0;
+            now_playing.length = length;
+        }
+        self.taps.retain(|_, tap| tap.send(Feed::SongChange(song_name.clone())).is_ok());
+        if let Some(Err(e)) = self.archive.as_mut().map(|a| a.song_change(song_name.clone())) {
+            self.error(format!("archive: {}", e));
+        }
+        self.station
+            .events
+            .send(StationEvent::SongChange(self.station.number, song_name, announce))
+            .ok();
+    }
+
+    fn set_health(&self, health: Health) {
+        let song_name = {
+            let mut now_playing = self.station.now_playing.lock().unwrap();
+            if mem::replace(&mut now_playing.health, health) == health {
+                return;
+            }
+            now_playing.song_name.clone()
+        };
+        self.station
+            .events
+            .send(StationEvent::HealthChange(self.station.number, health, song_name))
+            .ok();
+    }
+
+    /// Switch to `source`, reporting what it plays and the health that comes with it.
+    fn switch(&mut self, source: &Source, health: Health) {
+        let (song_name, length) = source.song();
+        self.set_song(song_name, length, false);
+        self.set_health(health);
+    }
+
+    /// Open the fallback source, if there is one and it works.
+    fn open_fallback(&mut self) -> Option<Source> {
+        let spec = self.station.config.fallback.clone()?;
+        match Source::open(&spec, &self.station) {
+            Ok(source) => {
+                self.switch(&source, Health::Fallback);
+                Some(source)
+            }
+            Err(e) => {
+                self.error(format!("fallback {}: {}", spec, e));
+                None
+            }
+        }
+    }
+
+    /// Send `packet` to every recipient and tap.
+    fn send(&mut self, sock: &UdpSocket, packet: &[u8]) {
+        self.station.now_playing.lock().unwrap().offset += packet.len() as u64;
+        if let Some(ref mut time_shift) = self.time_shift {
+            time_shift.push(packet);
+        }
+        if let Some(Err(e)) = self.archive.as_mut().map(|archive| archive.write(packet)) {
+            self.error(format!("archive: {}", e));
+        }
+
+        // sinks shared by several connections still only get every packet once
+        let mut failed = Vec::new();
+        {
+            let destinations: HashSet<&UdpAddress> = self.recipients
+                .values()
+                .flat_map(|sinks| sinks.iter())
+                .collect();
+            for recipient in destinations {
+                debug!("rec: {:?}", recipient);
+                if let Err(e) = self.send_to(sock, recipient, packet) {
+                    if is_permanent_send_error(&e) {
+                        failed.push((*recipient, e));
+                    }
+                }
+            }
+        }
+        for (sink, e) in failed {
+            self.drop_sink(sink, e);
+        }
+
+        self.taps.retain(|_, tap| tap.send(Feed::Audio(packet.to_vec())).is_ok());
+    }
+
+    /// Send the sinks that just joined the audio kept for them, at once so that their players
+    /// start right away.
+    ///
+    /// Hosts that were sent a burst lately get none, and the bursts count towards the rate limit
+    /// of their host like any audio.
+    fn burst(&mut self, sock: &UdpSocket) {
+        if self.joined.is_empty() {
+            return;
+        }
+        let packets = self.time_shift.as_ref().map_or_else(Vec::new, |ts| ts.burst(PACKET_SIZE));
+        let now = Instant::now();
+        let interval = Duration::from_secs(BURST_INTERVAL_SECS);
+        self.bursts.retain(|_, &mut sent| now.duration_since(sent) < interval);
+        for sink in mem::take(&mut self.joined) {
+            if !self.recipients.values().any(|sinks| sinks.contains(&sink)) ||
+               self.bursts.contains_key(&sink.0) {
+                continue;
+            }
+            self.bursts.insert(sink.0, now);
+            debug!("sending {} packets to {:?} at once", packets.len(), sink);
+            for packet in &packets {
+                if let Err(e) = self.send_to(sock, &sink, packet) {
+                    if is_permanent_send_error(&e) {
+                        self.drop_sink(sink, e);
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Send `packet` to `recipient` unless that goes over its rate limit.
+    fn send_to(&self, sock: &UdpSocket, recipient: &UdpAddress, packet: &[u8]) -> io::Result<()> {
+        let StationContext { ref limiter, ref metrics, .. } = self.station;
+        if let Some(ref limiter) = *limiter {
+            if !limiter.lock().unwrap().allow(recipient.0, packet.len()) {
+                trace!("rate limit reached for {}", recipient.0);
+                return Ok(());
+            }
+        }
+        let dest = SocketAddr::new(IpAddr::V4(recipient.0), recipient.1);
+        match sock.send_to(packet, &dest) {
+            Ok(n) => {
+                metrics.packets_sent.fetch_add(1, Ordering::Relaxed);
+                metrics.bytes_sent.fetch_add(n as u64, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                debug!("failed to send to {}: {:?}", dest, e);
+                metrics.send_errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    /// Stop sending to `sink`, which cannot be sent to, rather than trying again with every
+    /// packet.
+    fn drop_sink(&mut self, sink: UdpAddress, e: io::Error) {
+        for sinks in self.recipients.values_mut() {
+            sinks.retain(|s| *s != sink);
+        }
+        self.station
+            .events
+            .send(StationEvent::SinkFailed(self.station.number, sink, e.to_string()))
+            .ok();
+    }
+}
+
+/// Whether sending to a sink failed in a way that retrying will not fix.
+fn is_permanent_send_error(e: &io::Error) -> bool {
+    matches!(e.kind(),
+             ErrorKind::ConnectionRefused | ErrorKind::PermissionDenied |
+             ErrorKind::AddrNotAvailable | ErrorKind::InvalidInput | ErrorKind::HostUnreachable |
+             ErrorKind::NetworkUnreachable)
+}
+
+/// Stream `station` to the recipients `rx` tells about, until the server goes away.
+///
+/// When the primary source fails, the station switches to its fallback, if it has one, and is
+/// offline otherwise, playing silence to keep the listeners' decoders in sync. The primary is
+/// tried again after a backoff, which doubles with every failure in a row, and switched back to
+/// once it works. Recipients are kept throughout.
+pub fn broadcast_channel(rx: Receiver<Action>, station: StationContext) {
+    let time_shift = station.time_shift.map(TimeShift::new);
+    let archive = station.config.archive.clone().map(|dir| {
+        let song_name = station.now_playing.lock().unwrap().song_name.clone();
+        Archive::new(dir, station.number, song_name)
+    });
+    let mut broadcaster = Broadcaster {
+        station,
+        recipients: HashMap::new(),
+        taps: HashMap::new(),
+        live: None,
+        metadata: None,
+        time_shift,
+        joined: Vec::new(),
+        bursts: HashMap::new(),
+        archive,
+    };
+    let min_backoff = Duration::from_secs(MIN_RETRY_BACKOFF_SECS);
+    let max_backoff = Duration::from_secs(MAX_RETRY_BACKOFF_SECS);
+    let mut backoff = min_backoff;
+
+    let addr = ("0.0.0.0:".to_string() + "0")
+        .parse::<SocketAddr>()
+        .unwrap();
+    let sock = loop {
+        match UdpSocket::bind(&addr) {
+            Ok(sock) => break sock,
+            Err(e) => {
+                broadcaster.error(format!("Failed to bind socket: {}; retrying in {}s",
+                                          e,
+                                          backoff.as_secs()));
+                broadcaster.set_health(Health::Offline);
+                if !broadcaster.wait(&rx, backoff) {
+                    return;
+                }
+                backoff = cmp::min(backoff * 2, max_backoff);
+            }
+        }
+    };
+    backoff = min_backoff;
+
+    let interval = Duration::new(0, PACKET_INTERVAL_NS);
+    let mut source: Option<(Source, Health)> = None;
+    let mut offline_silence = SilenceSource::new(broadcaster.station.silence_bitrate);
+    let mut retry_at = Instant::now();
+    let mut primary_since = Instant::now();
+    let mut last_packet: Option<Instant> = None;
+    let mut buffer = [0; PACKET_SIZE];
+    loop {
+        let now = Instant::now();
+        if let Some(last_packet) = last_packet {
+            if let Some(lag) = now.duration_since(last_packet).checked_sub(interval) {
+                let metrics = &broadcaster.station.metrics;
+                metrics.pacing_lag_ns.fetch_add(lag.as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
+
+        if !broadcaster.drain(&rx) {
+            return;
+        }
+        broadcaster.burst(&sock);
+
+        let primary = broadcaster.station.config.primary.clone();
+        let primary_live = matches!(primary, SourceSpec::Live);
+        let on_primary = matches!(source, Some((_, Health::Online)));
+        if let Some((stream, song_name)) = broadcaster.live.take() {
+            match Source::live(stream, song_name) {
+                Ok(live) if primary_live => {
+                    broadcaster.switch(&live, Health::Online);
+                    source = Some((live, Health::Online));
+                    primary_since = now;
+                    backoff = min_backoff;
+                }
+                Ok(_) => broadcaster.error("dropping encoder of a station not live".to_string()),
+                Err(e) => broadcaster.error(format!("live: {}", e)),
+            }
+        } else if !on_primary && now >= retry_at {
+            match Source::open(&primary, &broadcaster.station) {
+                Ok(opened) => {
+                    broadcaster.switch(&opened, Health::Online);
+                    source = Some((opened, Health::Online));
+                    primary_since = now;
+                }
+                Err(e) => {
+                    // a live primary only comes back when an encoder connects
+                    if !primary_live {
+                        broadcaster.error(format!("{}: {}; retrying in {}s",
+                                                  primary,
+                                                  e,
+                                                  backoff.as_secs()));
+                    }
+                    retry_at = now + backoff;
+                    backoff = cmp::min(backoff * 2, max_backoff);
+                    if source.is_none() {
+                        source = broadcaster.open_fallback().map(|s| (s, Health::Fallback));
+                    }
+                    if source.is_none() {
+                        broadcaster.set_health(Health::Offline);
+                    }
+                }
+            }
+        }
+
+        if let Some(song_name) = broadcaster.metadata.take() {
+            if primary_live && on_primary {
+                broadcaster.set_song(song_name, 0, true);
+            }
+        }
+
+        let chunk = match source {
+            Some((ref mut source, _)) => source.next(&mut buffer),
+            None => Ok(Chunk::Audio(offline_silence.next(&mut buffer))),
+        };
+        match chunk {
+            Ok(Chunk::Audio(len)) => broadcaster.send(&sock, &buffer[..len]),
+            Ok(Chunk::Song(song_name, length, announce)) => {
+                broadcaster.set_song(song_name, length, announce)
+            }
+            Ok(Chunk::Nothing) => (),
+            Err(e) => {
+                let health = source.take().map_or(Health::Offline, |(_, health)| health);
+                if health == Health::Online {
+                    // a primary that played for a while before failing starts over with the
+                    // shortest backoff
+                    if now.duration_since(primary_since) > max_backoff {
+                        backoff = min_backoff;
+                    }
+                    if primary_live {
+                        broadcaster.error(format!("live: {}", e));
+                    } else {
+                        broadcaster.error(format!("{}: {}; retrying in {}s",
+                                                  primary,
+                                                  e,
+                                                  backoff.as_secs()));
+                    }
+                    retry_at = now + backoff;
+                    backoff = cmp::min(backoff * 2, max_backoff);
+                    source = broadcaster.open_fallback().map(|s| (s, Health::Fallback));
+                } else {
+                    broadcaster.error(format!("fallback: {}", e));
+                }
+                if source.is_none() {
+                    broadcaster.set_health(Health::Offline);
+                }
+            }
+        }
+
+        let paced = source.as_ref().is_none_or(|(source, _)| source.paced());
+        last_packet = if paced { Some(now) } else { None };
+        if paced {
+            thread::sleep(interval); // 62.5ms
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use schedule::LocalTime;
+    use std::fs;
+    use std::process;
+
+    /// A clock that says what the test sets it to.
+    struct FixedClock(Arc<Mutex<LocalTime>>);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> LocalTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// A directory of files for a test, removed along with them once it is done.
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// What `chunk` is, with the byte the audio read into `buffer` is made of.
+    fn describe(chunk: Chunk, buffer: &[u8]) -> String {
+        match chunk {
+            Chunk::Audio(n) => format!("{} bytes of {}", n, buffer[0]),
+            Chunk::Song(song_name, length, announce) => {
+                let name = Path::new(&song_name).file_name().unwrap().to_string_lossy();
+                format!("{} of {} bytes, announced: {}", name, length, announce)
+            }
+            Chunk::Nothing => "nothing".to_string(),
+        }
+    }
+
+    /// Files of 1500 bytes each in a directory of their own, told apart by what they are made of.
+    fn write_files(name: &str, files: &[(&str, u8)]) -> TempDir {
+        let dir = std::env::temp_dir().join(format!("rustcast-station-{}-{}", process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        for &(file, byte) in files {
+            fs::write(dir.join(file), vec![byte; 1500]).unwrap();
+        }
+        TempDir(dir)
+    }
+
+    /// The next `count` chunks of `scheduled`.
+    fn next(scheduled: &mut Scheduled, count: usize) -> Vec<String> {
+        let mut buffer = [0u8; PACKET_SIZE];
+        (0..count).map(|_| describe(scheduled.next(&mut buffer).unwrap(), &buffer)).collect()
+    }
+
+    /// The next `count` chunks of `playlist`.
+    fn play(playlist: &mut Playlist, count: usize) -> Vec<String> {
+        let mut buffer = [0u8; PACKET_SIZE];
+        (0..count).map(|_| describe(playlist.next(&mut buffer).unwrap(), &buffer)).collect()
+    }
+
+    /// A schedule playing song.mp3 with a morning.mp3 slot from 06:00 to 07:00 and jingle.mp3 at
+    /// the top of the hour, and a clock for it set to Monday 05:30 of hour 100.
+    fn scheduled(name: &str) -> (Scheduled, Arc<Mutex<LocalTime>>, TempDir) {
+        let dir = write_files(name, &[("song.mp3", 1), ("morning.mp3", 2), ("jingle.mp3", 3)]);
+        let path = dir.0.join("schedule");
+        fs::write(&path,
+                  "* 06:00-07:00 morning.mp3\n\
+                   jingle jingle.mp3\n\
+                   default song.mp3\n")
+            .unwrap();
+        let schedule = schedule::load(path.to_str().unwrap()).unwrap();
+
+        let time = Arc::new(Mutex::new(LocalTime {
+            weekday: 0,
+            minute: 5 * 60 + 30,
+            hour: 100,
+        }));
+        let clock = Box::new(FixedClock(time.clone()));
+        let scheduled = Scheduled::open(schedule, None, clock).unwrap();
+        (scheduled, time, dir)
+    }
+
+    #[test]
+    fn the_default_playlist_plays_outside_of_the_slots() {
+        let (mut scheduled, _, _dir) = scheduled("default");
+        assert_eq!(next(&mut scheduled, 3),
+                   ["1024 bytes of 1",
+                    "476 bytes of 1",
+                    "song.mp3 of 1500 bytes, announced: false"]);
+    }
+
+    #[test]
+    fn the_jingle_plays_at_the_top_of_the_hour_and_the_slot_after_it() {
+        let (mut scheduled, time, _dir) = scheduled("jingle");
+        assert_eq!(next(&mut scheduled, 1), ["1024 bytes of 1"]);
+
+        *time.lock().unwrap() = LocalTime {
+            weekday: 0,
+            minute: 6 * 60,
+            hour: 101,
+        };
+        assert_eq!(next(&mut scheduled, 5),
+                   ["jingle.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 3",
+                    "476 bytes of 3",
+                    "morning.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 2"]);
+    }
+
+    #[test]
+    fn the_playlist_carries_on_after_the_jingle() {
+        let (mut scheduled, time, _dir) = scheduled("resume");
+        assert_eq!(next(&mut scheduled, 1), ["1024 bytes of 1"]);
+
+        // the jingle waits for the hour to change, not for a minute
+        time.lock().unwrap().minute = 5 * 60 + 59;
+        assert_eq!(next(&mut scheduled, 1), ["476 bytes of 1"]);
+
+        time.lock().unwrap().hour = 101;
+        assert_eq!(next(&mut scheduled, 4),
+                   ["jingle.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 3",
+                    "476 bytes of 3",
+                    "song.mp3 of 1500 bytes, announced: true"]);
+        // and only once that hour
+        assert_eq!(next(&mut scheduled, 2),
+                   ["song.mp3 of 1500 bytes, announced: false", "1024 bytes of 1"]);
+    }
+
+    #[test]
+    fn hidden_jingles_play_without_changing_the_song() {
+        let dir = write_files("hidden", &[("song.mp3", 1), ("jingle.mp3", 3)]);
+        let jingles = Jingles {
+            files: vec![dir.0.join("jingle.mp3")],
+            every: JingleInterval::Files(1),
+            announce: false,
+        };
+        let mut playlist = Playlist::open(vec![dir.0.join("song.mp3")], Some(jingles)).unwrap();
+        assert_eq!(play(&mut playlist, 5),
+                   ["1024 bytes of 1",
+                    "476 bytes of 1",
+                    "1024 bytes of 3",
+                    "476 bytes of 3",
+                    "song.mp3 of 1500 bytes, announced: false"]);
+    }
+
+    #[test]
+    fn jingles_play_every_so_many_files() {
+        let dir = write_files("files", &[("a.mp3", 1), ("b.mp3", 2), ("jingle.mp3", 3)]);
+        let jingles = Jingles {
+            files: vec![dir.0.join("jingle.mp3")],
+            every: JingleInterval::Files(2),
+            announce: true,
+        };
+        let files = vec![dir.0.join("a.mp3"), dir.0.join("b.mp3")];
+        let mut playlist = Playlist::open(files, Some(jingles)).unwrap();
+        assert_eq!(play(&mut playlist, 10),
+                   ["1024 bytes of 1",
+                    "476 bytes of 1",
+                    "b.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 2",
+                    "476 bytes of 2",
+                    "jingle.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 3",
+                    "476 bytes of 3",
+                    "a.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 1"]);
+    }
+
+    #[test]
+    fn jingles_play_every_so_many_minutes() {
+        let dir = write_files("minutes", &[("song.mp3", 1), ("jingle.mp3", 3)]);
+        let jingles = Jingles {
+            files: vec![dir.0.join("jingle.mp3")],
+            every: JingleInterval::Minutes(1),
+            announce: true,
+        };
+        let mut playlist = Playlist::open(vec![dir.0.join("song.mp3")], Some(jingles)).unwrap();
+        // the first minute is not up yet
+        assert_eq!(play(&mut playlist, 4),
+                   ["1024 bytes of 1",
+                    "476 bytes of 1",
+                    "song.mp3 of 1500 bytes, announced: false",
+                    "1024 bytes of 1"]);
+
+        playlist.last_jingle = Instant::now().checked_sub(Duration::from_secs(60)).unwrap();
+        assert_eq!(play(&mut playlist, 8),
+                   ["476 bytes of 1",
+                    "jingle.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 3",
+                    "476 bytes of 3",
+                    "song.mp3 of 1500 bytes, announced: true",
+                    "1024 bytes of 1",
+                    "476 bytes of 1",
+                    "song.mp3 of 1500 bytes, announced: false"]);
+    }
+}
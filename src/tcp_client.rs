@@ -1,163 +1,275 @@
 extern crate byteorder;
 extern crate clap;
+extern crate hmac_sha256;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
-use byteorder::{ByteOrder, BigEndian};
+// shared with the server, of which clients only need the constants
+#[allow(dead_code)]
+mod commands;
+mod control;
+mod json;
+
 use clap::{App, Arg};
-use std::io;
-use std::io::prelude::*;
+use control::{Event, Reply};
+use std::io::{self, Write};
 use std::net::TcpStream;
-use std::time::Duration;
-use std::sync::mpsc::{Sender, Receiver};
+use std::process;
+use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a scripted run without `--duration` waits for the station's ANNOUNCE.
+const ANNOUNCE_TIMEOUT_SECS: u64 = 10;
+
+/// Exit statuses of a scripted run.
+#[derive(Clone, Copy)]
+enum ScriptStatus {
+    /// The station was set and announced, and the connection stayed up for the whole run.
+    Ok = 0,
+    /// The command line could not be parsed.
+    Usage = 1,
+    /// The server could not be reached or the handshake failed.
+    ConnectFailed = 2,
+    /// The server answered with an INVALID_COMMAND.
+    Rejected = 3,
+    /// The connection was lost or the server sent something we did not understand.
+    Disconnected = 4,
+    /// The server never sent an ANNOUNCE for the station.
+    NoAnnounce = 5,
+}
 
 fn main() {
-    env_logger::init().ok().expect("Failed to initialize logger");
+    env_logger::init().expect("Failed to initialize logger");
 
     let matches = App::new("rustcast_control")
         .version("0.1.0")
+        .about("Controls which station a rustcast server sends to a rustcast_listener.\n\n\
+                Servers started with --udp-challenge send no audio until the UDP port is \
+                verified by entering 'verify <code>' with the code rustcast_listener prints, \
+                again after every reconnect as each connection is challenged anew.")
         .arg(Arg::with_name("servername")
             .required(true)
             .index(1)
             .help("e.g. localhost OR 10.116.70.158"))
         .arg(Arg::with_name("serverport").required(true).index(2).help("e.g. 8001"))
+        .arg(Arg::with_name("auth")
+            .long("auth")
+            .takes_value(true)
+            .help("key to authenticate with for restricted stations, as <name>:<secret>"))
         .arg(Arg::with_name("udpport")
             .required(true)
             .index(3)
             .help("e.g. any port between 16384-16387"))
+        .arg(Arg::with_name("station")
+            .long("station")
+            .takes_value(true)
+            .help("set this station and exit instead of reading commands from stdin"))
+        .arg(Arg::with_name("duration")
+            .long("duration")
+            .takes_value(true)
+            .requires("station")
+            .help("keep listening for announces this long, e.g. 30s, 5m or 1h"))
+        .arg(Arg::with_name("json")
+            .long("json")
+            .requires("station")
+            .help("print announces and invalid command replies as JSON lines"))
         .get_matches();
 
     let servername = matches.value_of("servername").unwrap();
     debug!("server: {}", servername);
     let serverport = matches.value_of("serverport").unwrap().parse::<u16>().unwrap();
     debug!("server port: {}", serverport);
+    let credentials = matches.value_of("auth").map(|auth| {
+        control::Credentials::parse(auth).unwrap_or_else(|| {
+            eprintln!("Invalid credentials, expected <name>:<secret>");
+            process::exit(ScriptStatus::Usage as i32);
+        })
+    });
     let udpport = matches.value_of("udpport").unwrap().parse::<u16>().unwrap();
     debug!("udp port: {}", udpport);
 
-    let mut stream = TcpStream::connect((servername, serverport)).unwrap();
-    stream.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    if let Some(station) = matches.value_of("station") {
+        let station = station.parse::<u16>().unwrap_or_else(|_| {
+            eprintln!("Invalid station: {}", station);
+            process::exit(ScriptStatus::Usage as i32);
+        });
+        let duration = matches.value_of("duration").map(|duration| {
+            parse_duration(duration).unwrap_or_else(|| {
+                eprintln!("Invalid duration: {}", duration);
+                process::exit(ScriptStatus::Usage as i32);
+            })
+        });
+        let json = matches.is_present("json");
 
-    let mut hellobuf = [0u8; 3];
-    BigEndian::write_u16(&mut hellobuf[1..], udpport);
-    debug!("{:?}", hellobuf);
-    stream.write_all(hellobuf.as_ref()).unwrap();
+        let (stream, welcome) =
+            match control::connect(servername, serverport, udpport, credentials.as_ref()) {
+                Ok(connected) => connected,
+                Err(e) => {
+                    eprintln!("Failed to connect to {}:{}: {}", servername, serverport, e);
+                    process::exit(ScriptStatus::ConnectFailed as i32);
+                }
+            };
 
-    let mut welcomebuf = [0u8; 3];
-    stream.read_exact(&mut welcomebuf).unwrap();
-    let reply_type = welcomebuf[0];
-    let num_stations = BigEndian::read_u16(&welcomebuf[1..]);
-    info!("reply_type: {}, num_stations: {}", reply_type, num_stations);
+        let status = run_scripted(stream,
+                                  welcome.capabilities,
+                                  station,
+                                  duration,
+                                  json,
+                                  io::stdout());
+        process::exit(status as i32);
+    }
+
+    let first = match control::connect(servername, serverport, udpport, credentials.as_ref()) {
+        Ok(connected) => Some(connected),
+        Err(e) => {
+            eprintln!("Failed to connect to {}:{}: {}", servername, serverport, e);
+            None
+        }
+    };
 
     println!("Type in a number to set the station we're listening to to that number.");
+    println!("Enter ls to list the stations and what they are playing.");
+    println!("Enter np [station] to see how far into its song a station is.");
+    println!("Enter stop to stop the audio without disconnecting.");
+    println!("Enter sink add|rm <host>:<port> to send the audio to more places.");
+    println!("Enter verify <code> to answer a UDP challenge shown by rustcast_listener, again \
+              after every reconnect.");
     println!("Enter q or press CTRL+C to quit.");
-    println!("> The server has {} stations.", num_stations);
+    if let Some((_, ref welcome)) = first {
+        println!("> The server has {} stations.", welcome.num_stations);
+    }
 
-    let (tx, rx): (Sender<u16>, Receiver<u16>) = mpsc::channel();
+    let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel();
 
-    thread::spawn(move || client_loop(stream, rx));
+    let mut session = control::Session::new(servername, serverport, udpport, credentials);
+    let session_tx = tx.clone();
+    let session = thread::spawn(move || session.run(first, session_tx, rx, io::stdout()));
 
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
+    control::input_loop(tx, io::stdout());
+    session.join().ok();
+}
 
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(0) => {
-                info!("EndOfFile sent (Ctrl-D)");
-                break;
-            }
-            Ok(_) => {
-                match input.trim() {
-                    "q" => break,
-                    x => {
-                        let stationres = x.parse::<u16>();
-                        let station = match stationres {
-                            Ok(num) => num,
-                            Err(_) => {
-                                println!("Invalid input: number or 'q' expected");
-                                continue;
-                            }
-                        };
-                        tx.send(station).unwrap();
-                    }
-                }
-            }
-            Err(_) => {
-                panic!("Unexpected error reading from stdin");
-            }
-        }
+/// Parse durations such as `500ms`, `30s`, `5m` or `1h`; a bare number is taken as seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value = s[..split].parse::<u64>().ok()?;
+    match &s[split..] {
+        "ms" => Some(Duration::from_millis(value)),
+        "" | "s" => Some(Duration::from_secs(value)),
+        "m" => value.checked_mul(60).map(Duration::from_secs),
+        "h" => value.checked_mul(60 * 60).map(Duration::from_secs),
+        _ => None,
     }
 }
 
-fn client_loop(mut stream: TcpStream, rx: Receiver<u16>) {
-    loop {
-        let station = match rx.try_recv() {
-            Ok(station) => station,
-            Err(mpsc::TryRecvError::Empty) => 65535,
-            Err(mpsc::TryRecvError::Disconnected) => return,
-        };
+/// Set `station` and report the server's replies until `duration` has passed.
+///
+/// Without a duration the run ends with the first ANNOUNCE for the station.
+fn run_scripted<W: Write>(mut stream: TcpStream,
+                          capabilities: u16,
+                          station: u16,
+                          duration: Option<Duration>,
+                          json: bool,
+                          mut out: W)
+                          -> ScriptStatus {
+    if let Err(e) = control::send_set_station(&mut stream, station) {
+        eprintln!("Failed to send SET_STATION: {}", e);
+        return ScriptStatus::Disconnected;
+    }
 
-        if station < 65535 {
-            let mut setstationbuf = [0u8; 3];
-            setstationbuf[0] = 1;
-            BigEndian::write_u16(&mut setstationbuf[1..], station);
-            debug!("{:?}", setstationbuf);
-            stream.write_all(setstationbuf.as_ref()).unwrap();
+    let (tx, rx): (Sender<Event>, Receiver<Event>) = mpsc::channel();
+    if let Err(e) = control::spawn_reader(&stream, 0, capabilities, tx) {
+        eprintln!("Failed to read from the server: {}", e);
+        return ScriptStatus::Disconnected;
+    }
 
-            println!("Waiting for an announce…");
-        }
+    let timeout = duration.unwrap_or_else(|| Duration::from_secs(ANNOUNCE_TIMEOUT_SECS));
+    let deadline = Instant::now() + timeout;
+    let mut announced = false;
 
-        let mut reply_type_buf = [0u8; 1];
+    while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+        let reply = match rx.recv_timeout(timeout) {
+            Ok(Event::Reply(_, reply)) => reply,
+            Ok(_) | Err(RecvTimeoutError::Disconnected) => {
+                eprintln!("Lost connection to the server");
+                return ScriptStatus::Disconnected;
+            }
+            Err(RecvTimeoutError::Timeout) => break,
+        };
 
-        // poll server for change of song
-        match stream.read_exact(&mut reply_type_buf) {
-            Ok(_) => (),
-            Err(_) => continue,
-        }
+        match reply {
+            Reply::Announce { song_name } => {
+                announced = true;
+                if json {
+                    writeln!(out,
+                             "{{\"type\":\"announce\",\"station\":{},\"song_name\":{}}}",
+                             station,
+                             json::quote(&song_name))
+                        .unwrap();
+                } else {
+                    writeln!(out, "New song announced: {}", song_name).unwrap();
+                }
+                out.flush().unwrap();
 
-        info!("{}", reply_type_buf[0]);
-        match reply_type_buf[0] {
-            0 => {
-                error!("Server resent Welcome");
-                break;
+                if duration.is_none() {
+                    return ScriptStatus::Ok;
+                }
+            }
+            Reply::InvalidCommand { reply_string } => {
+                if json {
+                    writeln!(out,
+                             "{{\"type\":\"invalid_command\",\"station\":{},\"reply_string\":{}}}",
+                             station,
+                             json::quote(&reply_string))
+                        .unwrap();
+                } else {
+                    writeln!(out, "INVALID_COMMAND_REPLY: {}", reply_string).unwrap();
+                }
+                out.flush().unwrap();
+                return ScriptStatus::Rejected;
+            }
+            Reply::Ping { sequence } => {
+                if let Err(e) = control::send_pong(&mut stream, sequence) {
+                    eprintln!("Lost connection to the server: {}", e);
+                    return ScriptStatus::Disconnected;
+                }
+            }
+            Reply::StationStatus { station_number, health } => {
+                if json {
+                    writeln!(out,
+                             "{{\"type\":\"station_status\",\"station\":{},\"health\":{}}}",
+                             station_number,
+                             json::quote(health.name()))
+                        .unwrap();
+                } else {
+                    writeln!(out, "Station {} is {}", station_number, health.name()).unwrap();
+                }
+                out.flush().unwrap();
             }
-            1 => {
-                debug!("Announce");
-                let mut song_name_size = [0u8; 1];
-                stream.read_exact(&mut song_name_size).unwrap();
-                info!("{}", song_name_size[0]);
-                let song_name_size = song_name_size[0] as usize;
-                let mut song_name = vec![0u8; song_name_size];
-                stream.read_exact(&mut song_name).unwrap();
-
-                println!("New song announced: {}",
-                         String::from_utf8(song_name).unwrap());
-                print!("> ", );
-                io::stdout().flush().unwrap();
+            Reply::Goodbye => {
+                eprintln!("Server said goodbye");
+                return ScriptStatus::Disconnected;
             }
-            2 => {
-                // client sent an InvalidCommand
-                let mut reply_string_size = [0u8; 1];
-                stream.read_exact(&mut reply_string_size).unwrap();
-                info!("{}", reply_string_size[0]);
-                let mut reply_string = String::new();
-                assert_eq!(reply_string_size[0] as usize,
-                           stream.read_to_string(&mut reply_string).unwrap());
-                info!("{}", reply_string);
-
-                println!("INVALID_COMMAND_REPLY: {}", reply_string);
-                println!("Server has closed the connection.");
-                break;
+            Reply::StationList { .. } |
+            Reply::NowPlaying { .. } => (),
+            Reply::Welcome(_) => {
+                eprintln!("Server resent Welcome");
+                return ScriptStatus::Disconnected;
             }
-            _ => {
-                error!("Server sent an unknown response");
-                break;
+            Reply::Unknown { reply_type } => {
+                eprintln!("Server sent an unknown response: {}", reply_type);
+                return ScriptStatus::Disconnected;
             }
+        }
+    }
 
-        };
+    if announced {
+        ScriptStatus::Ok
+    } else {
+        ScriptStatus::NoAnnounce
     }
 }
@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Layer III bitrates in kbit/s by the index the header encodes them as, for MPEG-1 and for
+// MPEG-2 and 2.5; index 0 is free format, which is not supported
+const MPEG1_BITRATES: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256,
+                                   320];
+const MPEG2_BITRATES: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+
+// MPEG-1 sample rates in Hz; MPEG-2 halves them and MPEG-2.5 quarters them
+const SAMPLE_RATES: [u32; 3] = [44_100, 48_000, 32_000];
+
+/// The packets a station sent over the last while, for listeners that just tuned in.
+pub struct TimeShift {
+    // how far back packets are kept
+    length: Duration,
+
+    // packets in the order they were sent, with when they were
+    packets: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl TimeShift {
+    pub fn new(length: Duration) -> TimeShift {
+        TimeShift {
+            length,
+            packets: VecDeque::new(),
+        }
+    }
+
+    /// Keep `packet`, forgetting the packets that are now too old.
+    pub fn push(&mut self, packet: &[u8]) {
+        let now = Instant::now();
+        let length = self.length;
+        while self.packets.front().is_some_and(|&(sent, _)| now.duration_since(sent) > length) {
+            self.packets.pop_front();
+        }
+        self.packets.push_back((now, packet.to_vec()));
+    }
+
+    /// The audio kept, starting at the first whole frame, cut into packets of `packet_size`.
+    ///
+    /// Nothing is returned if no frame can be found.
+    pub fn burst(&self, packet_size: usize) -> Vec<Vec<u8>> {
+        let audio: Vec<u8> = self.packets
+            .iter()
+            .flat_map(|(_, packet)| packet.iter().cloned())
+            .collect();
+        match first_frame(&audio) {
+            Some(start) => audio[start..].chunks(packet_size).map(|chunk| chunk.to_vec()).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Where the first frame in `audio` starts, taking a header for one only if another follows it
+/// right after the frame, unless the frame runs to the end.
+fn first_frame(audio: &[u8]) -> Option<usize> {
+    (0..audio.len()).find(|&start| match frame_len(&audio[start..]) {
+        Some(len) if start + len + 4 <= audio.len() => frame_len(&audio[start + len..]).is_some(),
+        Some(_) => true,
+        None => false,
+    })
+}
+
+/// The length of the Layer III frame whose header `audio` starts with, if it does.
+pub fn frame_len(audio: &[u8]) -> Option<usize> {
+    if audio.len() < 4 || audio[0] != 0xff || audio[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    // 0 is MPEG-2.5, 2 MPEG-2 and 3 MPEG-1; 1 is reserved
+    let version = (audio[1] >> 3) & 0x03;
+    // 1 is Layer III
+    if version == 1 || (audio[1] >> 1) & 0x03 != 1 {
+        return None;
+    }
+    let bitrate_index = (audio[2] >> 4) as usize;
+    let sample_rate_index = ((audio[2] >> 2) & 0x03) as usize;
+    if bitrate_index == 0 || bitrate_index == 15 || sample_rate_index == 3 {
+        return None;
+    }
+    let padding = ((audio[2] >> 1) & 0x01) as u32;
+
+    let (bitrate, sample_rate, bytes_per_kbps) = match version {
+        3 => (MPEG1_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index], 144_000),
+        2 => (MPEG2_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index] / 2, 72_000),
+        _ => (MPEG2_BITRATES[bitrate_index], SAMPLE_RATES[sample_rate_index] / 4, 72_000),
+    };
+    Some((bytes_per_kbps * bitrate / sample_rate + padding) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 128 kbit/s, 44.1 kHz MPEG-1 Layer III frame, 417 bytes or 418 if padded, whose data is
+    /// all `fill`.
+    fn frame(padding: bool, fill: u8) -> Vec<u8> {
+        let mut frame = vec![0xff, 0xfb, 0x90 | (padding as u8) << 1, 0xc0];
+        frame.resize(417 + padding as usize, fill);
+        frame
+    }
+
+    /// Frames one after the other, told apart by their data.
+    fn frames(count: usize) -> Vec<u8> {
+        (0..count).flat_map(|i| frame(i % 3 != 0, i as u8 + 1)).collect()
+    }
+
+    #[test]
+    fn frame_len_reads_the_header() {
+        assert_eq!(frame_len(&frame(false, 0)), Some(417));
+        assert_eq!(frame_len(&frame(true, 0)), Some(418));
+        // MPEG-2, 64 kbit/s, 22.05 kHz
+        assert_eq!(frame_len(&[0xff, 0xf3, 0x80, 0xc0]), Some(208));
+        // MPEG-2.5, 8 kbit/s, 11.025 kHz
+        assert_eq!(frame_len(&[0xff, 0xe3, 0x10, 0xc0]), Some(52));
+
+        // too short, no sync word, reserved version, Layer I, free format, bad bitrate and bad
+        // sample rate
+        let headers: [&[u8]; 7] = [&[0xff, 0xfb, 0x90],
+                                   &[0xfe, 0xfb, 0x90, 0xc0],
+                                   &[0xff, 0xeb, 0x90, 0xc0],
+                                   &[0xff, 0xff, 0x90, 0xc0],
+                                   &[0xff, 0xfb, 0x00, 0xc0],
+                                   &[0xff, 0xfb, 0xf0, 0xc0],
+                                   &[0xff, 0xfb, 0x9c, 0xc0]];
+        for header in &headers {
+            assert_eq!(frame_len(header), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn first_frame_skips_the_rest_of_a_frame() {
+        let audio = frames(4);
+        for &cut in &[1, 2, 4, 100, 416] {
+            assert_eq!(first_frame(&audio[cut..]), Some(417 - cut), "cut at {}", cut);
+        }
+        assert_eq!(first_frame(&audio), Some(0));
+    }
+
+    #[test]
+    fn first_frame_skips_what_only_looks_like_a_header() {
+        let mut audio = frames(3);
+        // a header in the data of the first frame, not followed by another where it says
+        audio[150..154].copy_from_slice(&frame(false, 0)[..4]);
+        assert_eq!(first_frame(&audio[100..]), Some(317));
+    }
+
+    #[test]
+    fn first_frame_takes_a_frame_that_runs_to_the_end() {
+        let audio = frames(2);
+        // the rest of a frame and the start of the next, which cannot be checked further
+        assert_eq!(first_frame(&audio[300..600]), Some(117));
+        assert_eq!(first_frame(&[0u8; 1000]), None);
+        assert_eq!(first_frame(&[]), None);
+    }
+
+    #[test]
+    fn burst_starts_at_a_frame_in_whole_packets() {
+        let audio = frames(40);
+        // the oldest packet kept starts in the middle of a frame
+        let kept = &audio[250..];
+        let mut time_shift = TimeShift::new(Duration::from_secs(60));
+        for packet in kept.chunks(1000) {
+            time_shift.push(packet);
+        }
+
+        let burst = time_shift.burst(1024);
+        assert_eq!(&burst[0][..2], &[0xff, 0xfb]);
+        assert!(burst[..burst.len() - 1].iter().all(|packet| packet.len() == 1024));
+        let sent: Vec<u8> = burst.concat();
+        assert!(sent[..] == kept[167..]);
+    }
+
+    #[test]
+    fn burst_is_empty_without_a_frame() {
+        let mut time_shift = TimeShift::new(Duration::from_secs(60));
+        assert!(time_shift.burst(1024).is_empty());
+        time_shift.push(&[0u8; 1024]);
+        assert!(time_shift.burst(1024).is_empty());
+    }
+
+    #[test]
+    fn push_forgets_packets_older_than_the_length() {
+        let mut time_shift = TimeShift::new(Duration::from_millis(0));
+        time_shift.push(&frames(1));
+        std::thread::sleep(Duration::from_millis(5));
+        time_shift.push(&[0u8; 10]);
+        assert_eq!(time_shift.packets.len(), 1);
+        assert!(time_shift.burst(1024).is_empty());
+    }
+}
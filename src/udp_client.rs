@@ -1,18 +1,29 @@
+extern crate byteorder;
 extern crate clap;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
 
+// shared with the server, of which clients only need the constants
+#[allow(dead_code)]
+mod commands;
+mod listen;
+
 use clap::{App, Arg};
 use std::net::UdpSocket;
-use std::io::{self, Write};
+use std::io;
 
 fn main() {
-    env_logger::init().ok().expect("Failed to initialize logger");
+    env_logger::init().expect("Failed to initialize logger");
 
     let matches = App::new("rustcast_listener")
         .version("0.1.0")
+        .about("Writes the audio a rustcast server sends to the given UDP port to stdout.\n\n\
+                Servers started with --udp-challenge send no audio until the port is verified: \
+                the code printed here has to be entered as 'verify <code>' in the \
+                rustcast_control of the session, again after every reconnect. rustcast_client \
+                does this by itself.")
         .arg(Arg::with_name("udpport")
             .required(true)
             .index(1)
@@ -24,9 +35,11 @@ fn main() {
 
     let socket = UdpSocket::bind(("0.0.0.0", port)).unwrap();
 
-    loop {
-        let mut buf = [0u8; 2048]; // unsure if this should match the server buffer size
-        let (amt, _) = socket.recv_from(&mut buf).unwrap();
-        io::stdout().write(&buf[0..amt]).unwrap();
-    }
+    listen::receive_loop(&socket, io::stdout(), |cookie| {
+        eprintln!("The server wants proof we receive on port {}; enter 'verify {:016x}' in \
+                   rustcast_control, which is needed again after it reconnects",
+                  port,
+                  cookie);
+    })
+        .unwrap();
 }
@@ -0,0 +1,74 @@
+//! Helpers shared by the tests that run the server.
+
+// each test uses only some of them
+#![allow(dead_code)]
+
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how long a server may take to start listening, and an answer or audio to arrive
+pub const TIMEOUT_SECS: u64 = 10;
+
+/// A server running for as long as the test needs it.
+pub struct Server {
+    child: Child,
+    pub port: u16,
+}
+
+impl Server {
+    pub fn start(args: &[&str]) -> Server {
+        // a port that was free a moment ago
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let child = Command::new(env!("CARGO_BIN_EXE_rustcast_server"))
+            .arg(port.to_string())
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start the server");
+        let server = Server { child, port };
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(Instant::now() < deadline, "server did not start listening");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+}
+
+impl Server {
+    /// Send the server a SIGHUP, which reloads its access list.
+    pub fn hang_up(&self) {
+        let status = Command::new("kill")
+            .arg("-HUP")
+            .arg(self.child.id().to_string())
+            .status()
+            .expect("Failed to run kill");
+        assert!(status.success());
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+/// Write `secs` seconds of silent 128 kbit/s MP3 frames to `path`.
+pub fn write_mp3(path: &Path, secs: u32) {
+    let mut audio = Vec::new();
+    // 44100 / 1152 frames a second, each 417 bytes or, padded, 418
+    let frames = secs * 44_100 / 1152;
+    for frame in 0..frames {
+        let padding = frame % 25 != 0;
+        let mut header = vec![0xff, 0xfb, 0x90 | (padding as u8) << 1, 0xc0];
+        header.resize(417 + padding as usize, 0);
+        audio.extend_from_slice(&header);
+    }
+    fs::write(path, audio).unwrap();
+}
@@ -0,0 +1,94 @@
+//! Relaying a station of one server through another, as a listener of the second sees it.
+
+mod common;
+
+use common::{write_mp3, Server, TIMEOUT_SECS};
+use std::env;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpStream, UdpSocket};
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Read the string of an ANNOUNCE, after its reply type.
+fn read_string(control: &mut TcpStream) -> String {
+    let mut len = [0u8; 1];
+    control.read_exact(&mut len).unwrap();
+    let mut s = vec![0u8; len[0] as usize];
+    control.read_exact(&mut s).unwrap();
+    String::from_utf8(s).unwrap()
+}
+
+#[test]
+fn listeners_of_a_relay_get_the_audio_and_songs_of_the_upstream_station() {
+    relay_through("127.0.0.1", "same");
+}
+
+#[test]
+fn relays_take_audio_the_upstream_sends_from_another_address() {
+    // connecting to 127.0.0.2 leaves the upstream sending the audio from 127.0.0.1, as a
+    // multi-homed server would
+    relay_through("127.0.0.2", "other");
+}
+
+/// Relay the station of an upstream server connected to at `upstream_host`, checking what a
+/// listener of the relay gets.
+fn relay_through(upstream_host: &str, name: &str) {
+    let dir = env::temp_dir().join(format!("rustcast-relay-{}-{}", process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    let song = dir.join("song.mp3");
+    let jingle = dir.join("jingle.mp3");
+    write_mp3(&song, 1);
+    write_mp3(&jingle, 1);
+
+    // the upstream station plays a jingle after every song, so its songs keep changing
+    let jingles = format!("0=1:{}", jingle.display());
+    let upstream = Server::start(&[song.to_str().unwrap(), "--jingles", &jingles]);
+    let relay = format!("relay:{}:{}/0", upstream_host, upstream.port);
+    let downstream = Server::start(&[&relay]);
+
+    let audio = UdpSocket::bind("127.0.0.1:0").unwrap();
+    audio.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+    let audio_port = audio.local_addr().unwrap().port();
+    let mut control = TcpStream::connect(("127.0.0.1", downstream.port)).unwrap();
+    control.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS))).unwrap();
+
+    control.write_all(&[0, (audio_port >> 8) as u8, audio_port as u8]).unwrap();
+    let mut welcome = [0u8; 3];
+    control.read_exact(&mut welcome).unwrap();
+    assert_eq!(welcome, [0, 0, 1]);
+
+    control.write_all(&[1, 0, 0]).unwrap();
+    // the relay may not be connected yet, in which case the first song is the relay itself; then
+    // the upstream station moves on from its song or jingle and the relay passes that on
+    let songs = [song.display().to_string(), jingle.display().to_string()];
+    let mut announced: Vec<String> = Vec::new();
+    while announced.len() < 2 {
+        let mut reply_type = [0u8; 1];
+        control.read_exact(&mut reply_type).unwrap();
+        assert_eq!(reply_type[0], 1);
+        let song_name = read_string(&mut control);
+        if songs.contains(&song_name) && !announced.contains(&song_name) {
+            announced.push(song_name);
+        }
+    }
+
+    let mut datagram = [0u8; 2048];
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT_SECS);
+    let mut received = 0;
+    while received < 16 * 1024 {
+        assert!(Instant::now() < deadline, "relayed audio did not arrive");
+        match audio.recv_from(&mut datagram) {
+            Ok((len, _)) => {
+                // silent frames are nothing but headers and zeros
+                let silent = |b: &u8| [0x00, 0xff, 0xfb, 0x90, 0x92, 0xc0].contains(b);
+                assert!(datagram[..len].iter().all(silent));
+                received += len;
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
+            Err(e) => panic!("receiving audio failed: {}", e),
+        }
+    }
+
+    fs::remove_dir_all(&dir).ok();
+}